@@ -55,10 +55,10 @@ async fn internal_make_request(client: &reqwest::Client, url:&str) -> Result<Cap
         match serde_path_to_error::deserialize(ds) {
             Ok(content)=>return Ok(content),
             Err(e)=>{
-                println!("ERROR could not unmarshal content: {}", e);
+                eprintln!("ERROR could not unmarshal content: {}", e);
                 let body_string:Vec<u8> = body.into_iter().collect();
                 let content_string = String::from_utf8(body_string).unwrap_or(String::from("(not utf)"));
-                println!("Body was: {}", content_string);
+                eprintln!("Body was: {}", content_string);
                 return Err(Box::new(e))
             }
         }
@@ -83,6 +83,7 @@ pub async fn make_capi_request(client: &reqwest::Client, capi_key:String, query_
         ("show-tags", String::from("all")),
         ("tag", query_tag),
         ("show-blocks", String::from("all")),
+        ("show-fields", String::from("standfirst")),
         ("page", format!("{}", page_counter)),
         ("page-size", format!("{}", page_size))
     ]);
@@ -609,6 +610,11 @@ mod tests {
         assert_eq!(returned_content.response.currentPage, 1);
         assert_eq!(returned_content.response.results.len(), 1);
         assert_eq!(returned_content.response.results[0].blocks.body.len(), 1);
+        assert_eq!(returned_content.response.results[0].blocks.main.block.id, "6527e7ef8f0830b0d91d87b3");
+        assert_eq!(returned_content.response.results[0].blocks.main.elements.len(), 1);
+        assert_eq!(returned_content.response.results[0].blocks.main.elements[0].r#type, "image");
+        assert_eq!(returned_content.response.results[0].blocks.main.elements[0].imageTypeData.as_ref().and_then(|d| d.credit.as_deref()), Some("Photograph: Akarma/Victoria and Albert Museum, London"));
+        assert!(returned_content.response.results[0].standfirst().is_none());
         capi_mock.assert_hits(1);
     }
 
@@ -1,33 +1,191 @@
 use crate::models::*;
-use core::slice::Iter;
+use std::collections::HashMap;
+use clap::ValueEnum;
 
-fn recursive_chopper(mut i:Iter<CapiBlock>, mut summaries:Vec<SummarisedContent>, mut current:SummarisedContent) -> Vec<SummarisedContent>{
-    match i.next() {
-        Some(block)=>
-            if block.attributes.summary.unwrap_or(false) {   //we reached a summary, start a new block of summarised content
-                summaries.push(current);
-                return recursive_chopper(i, summaries, SummarisedContent::new(block.clone(), vec!()));
-            } else {
-                current.events.push(block.clone());
-                return recursive_chopper(i, summaries, current)
+/// A ChopStrategy decides where one segment of a liveblog ends and the next begins.
+/// It is given the body blocks as returned by `prepare_body`.
+/// Implementations only borrow the blocks, so the returned segments live as long as the container they came from.
+pub trait ChopStrategy {
+    fn chop<'a>(&self, blocks:&[&'a CapiBlock]) -> Vec<SummarisedContent<'a>>;
+}
+
+/// The names of the chopping strategies that can be selected from the commandline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChopStrategyName {
+    /// Start a new segment at each summary block (the default)
+    Summary,
+    /// Start a new segment whenever a block's publication time falls into a new fixed-length window
+    TimeWindow,
+    /// Start a new segment every N blocks
+    BlockCount,
+    /// Start a new segment at each key event block
+    KeyEvent,
+}
+
+/// Builds the chopping strategy for the given name.
+/// `window_minutes` is only used by the time-window strategy and `block_count` only by the block-count strategy.
+pub fn make_strategy(name:ChopStrategyName, window_minutes:u32, block_count:usize) -> Box<dyn ChopStrategy> {
+    match name {
+        ChopStrategyName::Summary=>Box::new(SummaryBoundary {}),
+        ChopStrategyName::TimeWindow=>Box::new(TimeWindow { window: chrono::Duration::minutes(i64::from(window_minutes)) }),
+        ChopStrategyName::BlockCount=>Box::new(BlockCountWindow { size: block_count }),
+        ChopStrategyName::KeyEvent=>Box::new(KeyEventBoundary {}),
+    }
+}
+
+/// Returns the body blocks that should be chopped.
+/// Pinned blocks are often repeated at the top of the body as well as appearing in their proper place, so only
+/// the last (i.e. chronological) occurrence of each pinned block id is kept.
+/// If `exclude_pinned` is set then pinned blocks are dropped altogether, unless they are summaries which are still needed
+/// to group the events.
+pub fn prepare_body(blocks:&CapiBlocksContainer, exclude_pinned:bool) -> Vec<&CapiBlock> {
+    let mut last_pinned_index:HashMap<&str, usize> = HashMap::new();
+    for (idx, block) in blocks.body.iter().enumerate() {
+        if block.attributes.pinned.unwrap_or(false) {
+            last_pinned_index.insert(&block.id, idx);
+        }
+    }
+
+    blocks.body.iter()
+        .enumerate()
+        .filter(|(idx, block)| {
+            match last_pinned_index.get(block.id.as_str()) {
+                None=>true,
+                Some(last_idx)=> *last_idx==*idx && (!exclude_pinned || block.attributes.summary.unwrap_or(false))
             }
-        None => {
-            summaries.push(current);
-            return summaries;
+        })
+        .map(|(_, block)| block)
+        .collect()
+}
+
+/// Splits the body of a liveblog into segments, starting a new segment at each summary block.
+/// Any blocks before the first summary go into a segment with no summary, which is always the first one returned.
+///
+/// This walks the blocks iteratively and only borrows them, so it is safe for arbitrarily long liveblogs.
+pub fn run_the_chopper<'a>(blocks:&[&'a CapiBlock]) -> Vec<SummarisedContent<'a>> {
+    let summary_count = blocks.iter().filter(|b| b.attributes.summary.unwrap_or(false)).count();
+    let mut summaries:Vec<SummarisedContent> = Vec::with_capacity(summary_count + 1);
+    let mut current = SummarisedContent::empty();
+
+    for &block in blocks.iter() {
+        if block.attributes.summary.unwrap_or(false) {   //we reached a summary, start a new block of summarised content
+            summaries.push(std::mem::replace(&mut current, SummarisedContent::new(block, vec!())));
+        } else {
+            current.push_event(block);
         }
     }
+
+    summaries.push(current);
+    summaries
 }
 
-pub fn run_the_chopper(blocks:&CapiBlocksContainer) -> Vec<SummarisedContent> {
-    let summarised_content = recursive_chopper(blocks.body.iter(), Vec::new(), SummarisedContent::empty());
+/// Splits the body into segments without summaries, starting a new one wherever `starts_segment` returns true.
+/// The block that starts a segment is the first event of that segment. Empty segments are never returned.
+fn chop_without_summaries<'a, F>(blocks:&[&'a CapiBlock], mut starts_segment:F) -> Vec<SummarisedContent<'a>>
+    where F: FnMut(usize, &CapiBlock) -> bool
+{
+    let mut segments:Vec<SummarisedContent> = vec!();
+    let mut current = SummarisedContent::empty();
 
-    return summarised_content;
+    for (idx, &block) in blocks.iter().enumerate() {
+        if starts_segment(idx, block) && !current.events.is_empty() {
+            segments.push(std::mem::replace(&mut current, SummarisedContent::empty()));
+        }
+        current.push_event(block);
+    }
+
+    if !current.events.is_empty() {
+        segments.push(current);
+    }
+    segments
+}
+
+/// Splits at each summary block, see `run_the_chopper`
+pub struct SummaryBoundary {}
+
+impl ChopStrategy for SummaryBoundary {
+    fn chop<'a>(&self, blocks:&[&'a CapiBlock]) -> Vec<SummarisedContent<'a>> {
+        run_the_chopper(blocks)
+    }
+}
+
+/// Groups blocks into fixed-length windows of publication time, e.g. hourly.
+/// Windows are aligned to the unix epoch so the same block always lands in the same window.
+/// Blocks with no usable timestamp stay in the window of the block before them.
+pub struct TimeWindow {
+    pub window: chrono::Duration,
+}
+
+impl ChopStrategy for TimeWindow {
+    fn chop<'a>(&self, blocks:&[&'a CapiBlock]) -> Vec<SummarisedContent<'a>> {
+        let window_secs = self.window.num_seconds().max(1);
+        let mut current_window:Option<i64> = None;
+
+        chop_without_summaries(blocks, |_, block| {
+            match block.first_published().map(|t| t.timestamp().div_euclid(window_secs)) {
+                Some(w)=>{
+                    let is_new = current_window.map(|cw| cw!=w).unwrap_or(false);
+                    current_window = Some(w);
+                    is_new
+                },
+                None=>false
+            }
+        })
+    }
+}
+
+/// Groups blocks into windows of a fixed number of blocks. The last window may be shorter.
+pub struct BlockCountWindow {
+    pub size: usize,
+}
+
+impl ChopStrategy for BlockCountWindow {
+    fn chop<'a>(&self, blocks:&[&'a CapiBlock]) -> Vec<SummarisedContent<'a>> {
+        let size = self.size.max(1);
+        chop_without_summaries(blocks, |idx, _| idx % size == 0)
+    }
+}
+
+/// Starts a new segment at each key event block, which becomes the first event of its segment
+pub struct KeyEventBoundary {}
+
+impl ChopStrategy for KeyEventBoundary {
+    fn chop<'a>(&self, blocks:&[&'a CapiBlock]) -> Vec<SummarisedContent<'a>> {
+        chop_without_summaries(blocks, |_, block| block.is_key_event())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use core::slice::Iter;
     use dyn_fmt::AsStrFormatExt;
+    use std::time::Instant;
+
+    /// The original recursive, cloning implementation of the chopper, kept for benchmarking against.
+    struct OwnedSummarisedContent {
+        #[allow(dead_code)]
+        summary: Option<CapiBlock>,
+        #[allow(dead_code)]
+        events: Vec<CapiBlock>,
+    }
+
+    fn recursive_chopper(mut i:Iter<CapiBlock>, mut summaries:Vec<OwnedSummarisedContent>, mut current:OwnedSummarisedContent) -> Vec<OwnedSummarisedContent>{
+        match i.next() {
+            Some(block)=>
+                if block.attributes.summary.unwrap_or(false) {
+                    summaries.push(current);
+                    recursive_chopper(i, summaries, OwnedSummarisedContent { summary: Some(block.clone()), events: vec!() })
+                } else {
+                    current.events.push(block.clone());
+                    recursive_chopper(i, summaries, current)
+                }
+            None => {
+                summaries.push(current);
+                summaries
+            }
+        }
+    }
 
     fn gen_blocks(block_count:u32,template_text:&str, summary_at:&[u32]) -> Vec<CapiBlock> {
         let mut out:Vec<CapiBlock> = vec!();
@@ -53,14 +211,16 @@ mod tests {
                     summary: Some(most_recent_summary),
                     title: Some(format!("Block {}", i)),
                     pinned: Some(false),
+                    keyEvent: Some(false),
                 },
-                firstPublishedDate: None,
+                //blocks are published ten minutes apart, starting at midnight
+                firstPublishedDate: Some(format!("2023-10-13T{:02}:{:02}:00Z", (i / 6) % 24, (i % 6) * 10)),
             });
 
             i-=1;
         }
 
-        return out;
+        out
     }
 
     #[test]
@@ -68,15 +228,18 @@ mod tests {
     pub fn test_chopper_multi_summary() {
         let summary_locations = [90, 80, 65, 33, 4];
         let blocks= CapiBlocksContainer { 
-            main: CapiBlock { 
-                id: "fake-main".to_owned(),
-                bodyHtml: "".to_owned(),
-                attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
-                firstPublishedDate: None,
+            main: CapiMainBlock {
+                block: CapiBlock { 
+                    id: "fake-main".to_owned(),
+                    bodyHtml: "".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: None,
+                },
+                elements: vec!(),
             },
             body: gen_blocks(99, "This is block number {}", &summary_locations),
         };
-        let result = run_the_chopper(&blocks);
+        let result = run_the_chopper(&prepare_body(&blocks, false));
 
         assert_eq!(result.len(), 6);
         assert!(result[0].summary.is_none());
@@ -91,4 +254,255 @@ mod tests {
         assert_eq!(result[5].summary.as_ref().map(|v| v.id.as_str()), Some("4"));
         assert_eq!(result[5].events.len(), 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_chopper_no_summary() {
+        let blocks = CapiBlocksContainer {
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fake-main".to_owned(),
+                    bodyHtml: "".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: None,
+                },
+                elements: vec!(),
+            },
+            body: gen_blocks(10, "This is block number {}", &[0]),
+        };
+        let result = run_the_chopper(&prepare_body(&blocks, false));
+
+        assert_eq!(result.len(), 1);
+        assert!(result[0].summary.is_none());
+        assert_eq!(result[0].events.len(), 9);
+    }
+
+    #[test]
+    pub fn test_chopper_very_long_liveblog() {
+        let summary_locations = [150000, 100000, 2];
+        let blocks = CapiBlocksContainer {
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fake-main".to_owned(),
+                    bodyHtml: "".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: None,
+                },
+                elements: vec!(),
+            },
+            body: gen_blocks(200001, "This is block number {}", &summary_locations),
+        };
+        let result = run_the_chopper(&prepare_body(&blocks, false));
+
+        assert_eq!(result.len(), 4);
+        assert_eq!(result[0].events.len(), 50000);
+        assert_eq!(result[1].summary.as_ref().map(|v| v.id.as_str()), Some("150000"));
+        assert_eq!(result[1].events.len(), 49999);
+        assert_eq!(result[2].summary.as_ref().map(|v| v.id.as_str()), Some("100000"));
+        assert_eq!(result[2].events.len(), 99997);
+        assert_eq!(result[3].summary.as_ref().map(|v| v.id.as_str()), Some("2"));
+        assert_eq!(result[3].events.len(), 1);
+    }
+
+    /// Compares the iterative chopper with the original recursive one.
+    /// Run with `cargo test --release -- --ignored --nocapture bench_chopper`
+    #[test]
+    #[ignore]
+    pub fn bench_chopper() {
+        let summary_locations = [1800, 1500, 1000, 500, 200];
+        let blocks = CapiBlocksContainer {
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fake-main".to_owned(),
+                    bodyHtml: "".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: None,
+                },
+                elements: vec!(),
+            },
+            body: gen_blocks(2000, "This is block number {}, with a bit more text to make the copying realistic", &summary_locations),
+        };
+        let iterations = 100;
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let result = run_the_chopper(&prepare_body(&blocks, false));
+            assert_eq!(result.len(), 6);
+        }
+        let iterative_time = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let result = recursive_chopper(blocks.body.iter(), Vec::new(), OwnedSummarisedContent { summary: None, events: vec!() });
+            assert_eq!(result.len(), 6);
+        }
+        let recursive_time = start.elapsed();
+
+        println!("iterative chopper: {:?} per run", iterative_time / iterations);
+        println!("recursive chopper: {:?} per run", recursive_time / iterations);
+    }
+
+    fn gen_container(block_count:u32, summary_at:&[u32]) -> CapiBlocksContainer {
+        CapiBlocksContainer {
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fake-main".to_owned(),
+                    bodyHtml: "".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: None,
+                },
+                elements: vec!(),
+            },
+            body: gen_blocks(block_count, "This is block number {}", summary_at),
+        }
+    }
+
+    #[test]
+    pub fn test_summary_boundary_strategy() {
+        let blocks = gen_container(99, &[90, 80, 65, 33, 4]);
+        let result = make_strategy(ChopStrategyName::Summary, 60, 20).chop(&prepare_body(&blocks, false));
+
+        assert_eq!(result.len(), 6);
+        assert_eq!(result[1].summary.as_ref().map(|v| v.id.as_str()), Some("90"));
+        assert_eq!(result[5].events.len(), 3);
+    }
+
+    #[test]
+    pub fn test_time_window_strategy() {
+        //blocks 1 to 23 are published from 00:10 to 03:50, newest first
+        let blocks = gen_container(24, &[0]);
+        let result = make_strategy(ChopStrategyName::TimeWindow, 60, 20).chop(&prepare_body(&blocks, false));
+
+        assert_eq!(result.len(), 4);
+        assert!(result.iter().all(|seg| seg.summary.is_none()));
+        assert_eq!(result[0].events.iter().map(|b| b.id.as_str()).collect::<Vec<_>>(), vec!["23", "22", "21", "20", "19", "18"]);
+        assert_eq!(result[1].events.len(), 6);
+        assert_eq!(result[2].events.len(), 6);
+        assert_eq!(result[3].events.iter().map(|b| b.id.as_str()).collect::<Vec<_>>(), vec!["5", "4", "3", "2", "1"]);
+    }
+
+    #[test]
+    pub fn test_time_window_strategy_missing_timestamps() {
+        let mut blocks = gen_container(12, &[0]);
+        //block "5" would start a new hour, but without a timestamp it stays with the block before it
+        blocks.body[6].firstPublishedDate = None;
+        let result = make_strategy(ChopStrategyName::TimeWindow, 60, 20).chop(&prepare_body(&blocks, false));
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].events.len(), 7);
+        assert_eq!(result[0].events.last().map(|b| b.id.as_str()), Some("5"));
+        assert_eq!(result[1].events.len(), 4);
+    }
+
+    #[test]
+    pub fn test_block_count_strategy() {
+        let blocks = gen_container(26, &[20]);
+        let result = make_strategy(ChopStrategyName::BlockCount, 60, 10).chop(&prepare_body(&blocks, false));
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].events.len(), 10);
+        assert_eq!(result[1].events.len(), 10);
+        assert_eq!(result[2].events.len(), 5);
+        //summary blocks are treated like any other block
+        assert_eq!(result[0].events[5].id, "20");
+    }
+
+    #[test]
+    pub fn test_key_event_strategy() {
+        let mut blocks = gen_container(20, &[0]);
+        blocks.body[4].attributes.keyEvent = Some(true);
+        blocks.body[10].attributes.keyEvent = Some(true);
+        let result = make_strategy(ChopStrategyName::KeyEvent, 60, 20).chop(&prepare_body(&blocks, false));
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].events.len(), 4);
+        assert_eq!(result[1].events[0].id, blocks.body[4].id);
+        assert_eq!(result[1].events.len(), 6);
+        assert_eq!(result[2].events[0].id, blocks.body[10].id);
+        assert_eq!(result[2].events.len(), 9);
+        assert!(result[0].key_events.is_empty());
+        assert_eq!(result[1].key_events, vec![blocks.body[4].id.as_str()]);
+    }
+
+    #[test]
+    pub fn test_key_events_listed_on_summary_segments() {
+        let mut blocks = gen_container(20, &[15, 5]);
+        blocks.body[1].attributes.keyEvent = Some(true);
+        blocks.body[4].attributes.keyEvent = Some(true);
+        blocks.body[6].attributes.keyEvent = Some(true);
+        blocks.body[9].attributes.keyEvent = Some(true);
+        let result = run_the_chopper(&prepare_body(&blocks, false));
+
+        assert_eq!(blocks.count_key_event_blocks(), 4);
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].key_events, vec!["18"]);
+        //the summary block itself can be a key event
+        assert_eq!(result[1].key_events, vec!["15", "13", "10"]);
+        assert!(result[2].key_events.is_empty());
+    }
+
+    #[test]
+    pub fn test_key_event_strategy_first_block() {
+        let mut blocks = gen_container(5, &[0]);
+        blocks.body[0].attributes.keyEvent = Some(true);
+        let result = make_strategy(ChopStrategyName::KeyEvent, 60, 20).chop(&prepare_body(&blocks, false));
+
+        //a key event at the very top must not produce an empty leading segment
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].events.len(), 4);
+    }
+
+    /// Copies `body[from]` to the top of the body as a pinned block, like CAPI does
+    fn pin_block(blocks:&mut CapiBlocksContainer, from:usize) {
+        blocks.body[from].attributes.pinned = Some(true);
+        let copy = blocks.body[from].clone();
+        blocks.body.insert(0, copy);
+    }
+
+    #[test]
+    pub fn test_pinned_summary_deduped() {
+        let mut blocks = gen_container(20, &[15, 5]);
+        pin_block(&mut blocks, 4);
+        let result = run_the_chopper(&prepare_body(&blocks, false));
+
+        //without de-duplication the pinned copy of summary 15 at the top would start an extra segment
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].events.len(), 4);
+        assert_eq!(result[1].summary.map(|v| v.id.as_str()), Some("15"));
+        assert_eq!(result[1].events.len(), 9);
+        assert_eq!(result[2].summary.map(|v| v.id.as_str()), Some("5"));
+
+        //the pinned copy isn't counted twice once the body is prepared
+        assert_eq!((blocks.count_body_blocks(), blocks.count_summary_blocks()), (20, 3));
+        let body = prepare_body(&blocks, false);
+        assert_eq!((body.count_body_blocks(), body.count_summary_blocks()), (19, 2));
+    }
+
+    #[test]
+    pub fn test_pinned_event_kept_in_place() {
+        let mut blocks = gen_container(20, &[15, 5]);
+        pin_block(&mut blocks, 8);
+        let body = prepare_body(&blocks, false);
+
+        assert_eq!(body.len(), 19);
+        assert_eq!(body[0].id, "19");
+        let result = run_the_chopper(&body);
+        assert_eq!(result[1].events.iter().filter(|b| b.id=="11").count(), 1);
+        assert_eq!(result[1].events.len(), 9);
+    }
+
+    #[test]
+    pub fn test_pinned_excluded() {
+        let mut blocks = gen_container(20, &[15, 5]);
+        pin_block(&mut blocks, 8);
+        pin_block(&mut blocks, 5);
+        let body = prepare_body(&blocks, true);
+
+        //the pinned event is dropped but the pinned summary still groups its events
+        assert_eq!(body.len(), 18);
+        let result = run_the_chopper(&body);
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[1].summary.map(|v| v.id.as_str()), Some("15"));
+        assert_eq!(result[1].events.len(), 8);
+        assert!(result[1].events.iter().all(|b| b.id!="11"));
+    }
+}
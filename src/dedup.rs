@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use clap::ValueEnum;
+use serde::Serialize;
+use crate::file_store::write_file_atomically;
+use crate::models::*;
+use crate::text::normalise_words;
+
+/// Name of the report of near-duplicate clusters, written at the top of the output path
+pub const DUPLICATES_FILE:&str = "DUPLICATES.jsonl";
+
+/// Number of hash functions in each MinHash signature
+const SIGNATURE_SIZE:usize = 128;
+
+/// Signatures are split into this many bands for locality-sensitive hashing. Two blocks are compared properly if all
+/// the rows of any band match; with 32 bands of 4 rows, pairs with a similarity of 0.5 are found 87% of the time and
+/// pairs with 0.8 almost always.
+const BANDS:usize = 32;
+const ROWS:usize = SIGNATURE_SIZE / BANDS;
+
+/// The Mersenne prime 2^61 - 1, which the hash functions work modulo
+const PRIME:u64 = (1 << 61) - 1;
+
+/// What to do with a block that is a near-duplicate of one seen earlier in the harvest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateAction {
+    /// Keep it, but list it in the segment's `duplicate_blocks`
+    Flag,
+    /// Leave it out: a duplicate summary drops its whole segment, and a duplicate event is taken out of its segment
+    Drop,
+}
+
+#[derive(Clone, Debug)]
+pub struct DedupOptions {
+    pub action: DuplicateAction,
+    /// Estimated Jaccard similarity of the shingles at or above which two blocks are near-duplicates
+    pub threshold: f64,
+    /// Number of words in each shingle
+    pub shingle_words: usize,
+}
+
+impl DedupOptions {
+    pub fn new(action:DuplicateAction, threshold:f64, shingle_words:usize) -> Result<DedupOptions, Box<dyn Error>> {
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            return Err("the duplicate threshold must be more than 0 and no more than 1".into());
+        }
+        if shingle_words==0 {
+            return Err("shingles need at least one word".into());
+        }
+        Ok(DedupOptions { action, threshold, shingle_words })
+    }
+}
+
+/// Summaries are only compared with summaries, and events with events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockKind {
+    Summary,
+    Event,
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is the same in every release
+fn fnv1a(bytes:&[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3))
+}
+
+/// SplitMix64, used to pick the coefficients of the hash functions from a fixed seed
+fn splitmix64(state:&mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The hash of each band of a signature, which is what the pool is indexed by
+fn band_hashes(signature:&[u32]) -> Vec<u64> {
+    signature.chunks(ROWS)
+        .map(|rows| fnv1a(&rows.iter().flat_map(|r| r.to_le_bytes()).collect::<Vec<u8>>()))
+        .collect()
+}
+
+/// Hashes of the overlapping runs of `size` words in the text, or None if it is too short to have any
+fn shingles(text:&str, size:usize) -> Option<Vec<u64>> {
+    let normalised = normalise_words(text);
+    let words:Vec<&str> = normalised.split(' ').filter(|w| !w.is_empty()).collect();
+    if words.len() < size {
+        return None;
+    }
+    let mut hashes:Vec<u64> = words.windows(size).map(|w| fnv1a(w.join(" ").as_bytes())).collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+    Some(hashes)
+}
+
+/// A block that has been seen, and the cluster of near-duplicates it belongs to
+struct SeenBlock {
+    capi_id: String,
+    block_id: String,
+    signature: Vec<u32>,
+    /// Index of the first block of its cluster
+    cluster: usize,
+    /// Estimated similarity to the block it was found to duplicate
+    similarity: Option<f64>,
+}
+
+/// The blocks of one kind that have been seen so far, indexed by band
+struct Pool {
+    blocks: Vec<SeenBlock>,
+    bands: Vec<HashMap<u64, Vec<usize>>>,
+}
+
+impl Pool {
+    fn new() -> Pool {
+        Pool { blocks: vec!(), bands: (0..BANDS).map(|_| HashMap::new()).collect() }
+    }
+
+    /// Forgets every block after the first `len`
+    fn truncate(&mut self, len:usize) {
+        for block in self.blocks.drain(len..) {
+            for (band, hash) in band_hashes(&block.signature).into_iter().enumerate() {
+                if let Some(indexes) = self.bands[band].get_mut(&hash) {
+                    indexes.retain(|i| *i < len);
+                    if indexes.is_empty() {
+                        self.bands[band].remove(&hash);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How much a `DuplicateDetector` had seen at some point, so that it can go back to it
+#[derive(Clone, Copy, Debug)]
+struct Checkpoint {
+    pool_sizes: [usize; 2],
+    duplicates_found: usize,
+}
+
+/// A block found to be a near-duplicate of an earlier one
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateMatch {
+    pub capi_id: String,
+    pub block_id: String,
+    pub similarity: f64,
+}
+
+/// Finds near-duplicate summaries and events across a whole harvest, using MinHash signatures of word shingles and
+/// locality-sensitive hashing to find candidates without comparing every pair. The first block of a cluster to be
+/// seen is the original and the rest are its duplicates.
+/// Each block seen is kept as a signature of `SIGNATURE_SIZE` numbers, about half a kilobyte.
+pub struct DuplicateDetector {
+    options: DedupOptions,
+    /// The multiplier and offset of each hash function
+    coefficients: Vec<(u64, u64)>,
+    pools: [Pool; 2],
+    duplicates_found: usize,
+}
+
+impl DuplicateDetector {
+    pub fn new(options:DedupOptions) -> DuplicateDetector {
+        let mut state = 0x6c69766562;
+        let coefficients = (0..SIGNATURE_SIZE)
+            .map(|_| ((splitmix64(&mut state) % (PRIME - 1)) + 1, splitmix64(&mut state) % PRIME))
+            .collect();
+        DuplicateDetector { options, coefficients, pools: [Pool::new(), Pool::new()], duplicates_found: 0 }
+    }
+
+    pub fn action(&self) -> DuplicateAction {
+        self.options.action
+    }
+
+    /// Number of blocks found to be near-duplicates so far
+    pub fn duplicates_found(&self) -> usize {
+        self.duplicates_found
+    }
+
+    fn signature(&self, shingles:&[u64]) -> Vec<u32> {
+        self.coefficients.iter()
+            .map(|(a, b)| {
+                shingles.iter()
+                    .map(|x| ((u128::from(*a) * u128::from(*x % PRIME) + u128::from(*b)) % u128::from(PRIME)) as u32)
+                    .min()
+                    .unwrap_or(u32::MAX)
+            })
+            .collect()
+    }
+
+    /// Compares a block with every block of the same kind seen so far, then remembers it.
+    /// Returns the closest earlier block if it is a near-duplicate. Blocks too short to make a shingle are ignored.
+    pub fn check(&mut self, kind:BlockKind, capi_id:&str, block:&CapiBlock) -> Option<DuplicateMatch> {
+        let shingles = shingles(&block.body_text(), self.options.shingle_words)?;
+        let signature = self.signature(&shingles);
+        let band_hashes = band_hashes(&signature);
+
+        let threshold = self.options.threshold;
+        let pool = &mut self.pools[kind as usize];
+        let mut candidates:Vec<usize> = band_hashes.iter().enumerate()
+            .filter_map(|(band, hash)| pool.bands[band].get(hash))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let best = candidates.into_iter()
+            .map(|idx| {
+                let matching = pool.blocks[idx].signature.iter().zip(signature.iter()).filter(|(a, b)| a==b).count();
+                (idx, matching as f64 / SIGNATURE_SIZE as f64)
+            })
+            .filter(|(_, similarity)| *similarity >= threshold)
+            .fold(None, |best:Option<(usize, f64)>, (idx, similarity)| match best {
+                Some((_, s)) if s >= similarity=>best,
+                _=>Some((idx, similarity)),
+            });
+
+        let idx = pool.blocks.len();
+        for (band, hash) in band_hashes.into_iter().enumerate() {
+            pool.bands[band].entry(hash).or_default().push(idx);
+        }
+        let cluster = best.map_or(idx, |(matched, _)| pool.blocks[matched].cluster);
+        let result = best.map(|(matched, similarity)| {
+            let m = &pool.blocks[matched];
+            DuplicateMatch { capi_id: m.capi_id.to_owned(), block_id: m.block_id.to_owned(), similarity }
+        });
+        pool.blocks.push(SeenBlock {
+            capi_id: capi_id.to_owned(),
+            block_id: block.id.to_owned(),
+            signature,
+            cluster,
+            similarity: best.map(|(_, s)| s),
+        });
+        if result.is_some() {
+            self.duplicates_found += 1;
+        }
+        result
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint { pool_sizes: [self.pools[0].blocks.len(), self.pools[1].blocks.len()], duplicates_found: self.duplicates_found }
+    }
+
+    /// Forgets every block seen since the checkpoint
+    fn rollback(&mut self, checkpoint:Checkpoint) {
+        for (pool, len) in self.pools.iter_mut().zip(checkpoint.pool_sizes) {
+            pool.truncate(len);
+        }
+        self.duplicates_found = checkpoint.duplicates_found;
+    }
+
+    /// Checks every block of a liveblog's segments, in order, and flags or drops the near-duplicates. Each segment
+    /// that is left is then passed to `keep`, which runs the filters that come after this one; if it returns false
+    /// then the segment is dropped and its blocks are forgotten again, so that later blocks are never counted as
+    /// duplicates of something that is not in the output.
+    pub fn process_segments<'a>(&mut self, capi_id:&str, segments:&mut Vec<SummarisedContent<'a>>, mut keep:impl FnMut(&mut SummarisedContent<'a>) -> bool) {
+        let action = self.options.action;
+        segments.retain_mut(|segment| {
+            let checkpoint = self.checkpoint();
+            if let Some(summary) = segment.summary {
+                if self.check(BlockKind::Summary, capi_id, summary).is_some() {
+                    match action {
+                        DuplicateAction::Drop=>return false,
+                        DuplicateAction::Flag=>segment.duplicate_blocks.push(&summary.id),
+                    }
+                }
+            }
+
+            let mut duplicate_events:Vec<&'a str> = vec!();
+            for event in segment.events.iter() {
+                if self.check(BlockKind::Event, capi_id, event).is_some() {
+                    duplicate_events.push(&event.id);
+                }
+            }
+            match action {
+                DuplicateAction::Drop=>segment.retain_events(|e| !duplicate_events.contains(&e.id.as_str())),
+                DuplicateAction::Flag=>segment.duplicate_blocks.extend(duplicate_events),
+            }
+            if keep(segment) {
+                true
+            } else {
+                self.rollback(checkpoint);
+                false
+            }
+        });
+    }
+
+    /// The clusters of near-duplicates found so far, each starting with the original block
+    pub fn clusters(&self) -> Vec<DuplicateCluster<'_>> {
+        let mut clusters = vec!();
+        for (kind, pool) in [BlockKind::Summary, BlockKind::Event].into_iter().zip(self.pools.iter()) {
+            let mut members:HashMap<usize, Vec<&SeenBlock>> = HashMap::new();
+            for block in pool.blocks.iter() {
+                members.entry(block.cluster).or_default().push(block);
+            }
+            let mut found:Vec<(usize, Vec<&SeenBlock>)> = members.into_iter().filter(|(_, m)| m.len() > 1).collect();
+            found.sort_by_key(|(first, _)| *first);
+            clusters.extend(found.into_iter().map(|(_, blocks)| DuplicateCluster {
+                kind,
+                blocks: blocks.into_iter().map(|b| ClusterMember { capi_id: &b.capi_id, block_id: &b.block_id, similarity: b.similarity }).collect(),
+            }));
+        }
+        clusters
+    }
+
+    /// Writes the clusters out as one JSON line each
+    pub fn write_report(&self, base_path:&Path) -> Result<(), Box<dyn Error>> {
+        let mut content:Vec<u8> = vec!();
+        for cluster in self.clusters() {
+            serde_json::to_writer(&mut content, &cluster)?;
+            content.push(b'\n');
+        }
+        write_file_atomically(&base_path.join(DUPLICATES_FILE), &content)
+    }
+}
+
+/// One block in a cluster. `similarity` is to the block it was found to duplicate, and is missing for the original.
+#[derive(Debug, Serialize)]
+pub struct ClusterMember<'a> {
+    pub capi_id: &'a str,
+    pub block_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f64>,
+}
+
+/// A group of blocks that are near-duplicates of each other
+#[derive(Debug, Serialize)]
+pub struct DuplicateCluster<'a> {
+    pub kind: BlockKind,
+    pub blocks: Vec<ClusterMember<'a>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::block;
+
+    const SUMMARY:&str = "The prime minister has announced a general election for the fourth of July, \
+        after weeks of speculation at Westminster. The opposition leader said the country was ready for change, \
+        and the campaign will formally begin when parliament is dissolved next week.";
+
+    fn detector(action:DuplicateAction) -> DuplicateDetector {
+        DuplicateDetector::new(DedupOptions::new(action, 0.7, 3).unwrap())
+    }
+
+    #[test]
+    pub fn test_finds_near_duplicates() {
+        let mut detector = detector(DuplicateAction::Flag);
+        let original = block("a").text(SUMMARY).build();
+        let edited = block("b").text(&SUMMARY.replace("next week", "on Thursday")).build();
+        let different = block("c").text("Heavy rain has caused flooding across the north of England, with several roads closed and train services cancelled between Leeds and Manchester this morning.").build();
+
+        assert_eq!(detector.check(BlockKind::Summary, "news/live/day-1", &original), None);
+        let found = detector.check(BlockKind::Summary, "news/live/day-2", &edited).unwrap();
+        assert_eq!((found.capi_id.as_str(), found.block_id.as_str()), ("news/live/day-1", "a"));
+        assert!(found.similarity > 0.7 && found.similarity < 1.0, "{}", found.similarity);
+        assert_eq!(detector.check(BlockKind::Summary, "news/live/day-2", &different), None);
+
+        //events are not compared with summaries
+        assert_eq!(detector.check(BlockKind::Event, "news/live/day-3", &original), None);
+        //too short to compare
+        assert_eq!(detector.check(BlockKind::Summary, "news/live/day-3", &block("d").text("Hi").build()), None);
+        assert_eq!(detector.duplicates_found(), 1);
+
+        let clusters = detector.clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].kind, BlockKind::Summary);
+        let members:Vec<(&str, &str)> = clusters[0].blocks.iter().map(|m| (m.capi_id, m.block_id)).collect();
+        assert_eq!(members, vec!(("news/live/day-1", "a"), ("news/live/day-2", "b")));
+        assert!(clusters[0].blocks[0].similarity.is_none());
+    }
+
+    #[test]
+    pub fn test_flag_and_drop_segments() {
+        let summary = block("summary").text(SUMMARY).build();
+        let repeated = block("repeated").text(SUMMARY).build();
+        let event = block("event").text("Polling stations will open at seven in the morning and close at ten at night.").build();
+        let event_again = block("event-again").text("Polling stations will open at seven in the morning and close at ten at night.").build();
+        let mut key_event = event_again.clone();
+        key_event.attributes.keyEvent = Some(true);
+
+        let mut flagging = detector(DuplicateAction::Flag);
+        let mut segments = vec!(SummarisedContent::new(&summary, vec!(&event)), SummarisedContent::new(&repeated, vec!(&event_again)));
+        flagging.process_segments("news/live/blog", &mut segments, |_| true);
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].duplicate_blocks.is_empty());
+        assert_eq!(segments[1].duplicate_blocks, vec!("repeated", "event-again"));
+
+        let mut dropping = detector(DuplicateAction::Drop);
+        let other_summary = block("other").text("Heavy rain has caused flooding across the north of England, with roads closed.").build();
+        let mut segments = vec!(
+            SummarisedContent::new(&summary, vec!(&event)),
+            SummarisedContent::new(&repeated, vec!()),
+            SummarisedContent::new(&other_summary, vec!(&key_event)),
+        );
+        dropping.process_segments("news/live/blog", &mut segments, |_| true);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].summary.unwrap().id, "other");
+        assert!(segments[1].events.is_empty());
+        assert!(segments[1].key_events.is_empty());
+        assert_eq!(dropping.duplicates_found(), 2);
+    }
+
+    #[test]
+    pub fn test_segments_dropped_later_are_forgotten() {
+        let summary = block("summary").text(SUMMARY).build();
+        let repeated = block("repeated").text(SUMMARY).build();
+        let event = block("event").text("Polling stations will open at seven in the morning and close at ten at night.").build();
+        let event_again = block("event-again").text("Polling stations will open at seven in the morning and close at ten at night.").build();
+
+        //the first segment is dropped by a later filter, so the second one is not a duplicate of anything kept
+        let mut detector = detector(DuplicateAction::Flag);
+        let mut segments = vec!(SummarisedContent::new(&summary, vec!(&event)), SummarisedContent::new(&repeated, vec!(&event_again)));
+        detector.process_segments("news/live/blog", &mut segments, |s| s.summary.unwrap().id!="summary");
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].duplicate_blocks.is_empty());
+        assert_eq!(detector.duplicates_found(), 0);
+        assert!(detector.clusters().is_empty());
+
+        //and a flagged duplicate that is dropped later does not count either
+        let mut segments = vec!(SummarisedContent::new(&summary, vec!()));
+        detector.process_segments("news/live/other", &mut segments, |_| false);
+        assert!(segments.is_empty());
+        assert_eq!(detector.duplicates_found(), 0);
+        assert_eq!(detector.check(BlockKind::Summary, "news/live/third", &summary).unwrap().block_id, "repeated");
+    }
+
+    #[test]
+    pub fn test_write_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut detector = detector(DuplicateAction::Flag);
+        for (capi_id, block_id) in [("news/live/day-1", "a"), ("news/live/day-2", "b"), ("news/live/day-3", "c")] {
+            detector.check(BlockKind::Summary, capi_id, &block(block_id).text(SUMMARY).build());
+        }
+        detector.write_report(dir.path()).unwrap();
+
+        let content = std::fs::read_to_string(dir.path().join(DUPLICATES_FILE)).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        let cluster:serde_json::Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert_eq!(cluster["kind"], "summary");
+        assert_eq!(cluster["blocks"][2]["capi_id"], "news/live/day-3");
+        assert_eq!(cluster["blocks"][2]["block_id"], "c");
+        assert_eq!(cluster["blocks"][2]["similarity"], 1.0);
+    }
+
+    #[test]
+    pub fn test_bad_options() {
+        assert!(DedupOptions::new(DuplicateAction::Drop, 0.0, 5).is_err());
+        assert!(DedupOptions::new(DuplicateAction::Drop, 1.5, 5).is_err());
+        assert!(DedupOptions::new(DuplicateAction::Drop, 0.8, 0).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use crate::manifest::ManifestFile;
+
+/// A FileStore is where the directory output layout ends up: either loose files on disk or entries in an archive.
+/// Paths given to it are always relative to the root of the output and use `/` as the separator.
+pub trait FileStore {
+    fn write_entry(&mut self, path:&str, content:&[u8]) -> Result<(), Box<dyn Error>>;
+    /// Removes an entry written by a previous run, if it exists. Archives are always written fresh so can ignore this.
+    fn remove_entry(&mut self, path:&str) -> Result<(), Box<dyn Error>>;
+    /// Reads back an entry written by a previous run, if it exists. Archives are always written fresh so return None.
+    fn read_entry(&self, path:&str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    /// Called once all the files for a liveblog have been written
+    fn liveblog_finished(&mut self) -> Result<(), Box<dyn Error>>;
+    /// Called once at the end of the run; nothing more can be written after this
+    fn close(&mut self) -> Result<(), Box<dyn Error>>;
+    /// The files on disk that this run has produced, for the dataset manifest
+    fn output_files(&self) -> Vec<PathBuf>;
+    /// Whether each entry is in its final place as soon as it is written, rather than when the store is closed
+    fn writes_through(&self) -> bool {
+        true
+    }
+    /// The objects this run has uploaded rather than written to disk, for the dataset manifest
+    fn uploaded_objects(&self) -> Vec<ManifestFile> {
+        vec!()
+    }
+}
+
+/// The archive formats that can be selected from the commandline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ArchiveFormat {
+    /// Write loose files, no archive
+    None,
+    TarGz,
+    TarZst,
+}
+
+/// Returns the highest N of the files named `{prefix}N{suffix}` in the given directory, if there are any.
+/// Writers that number their files use this to carry on after an earlier run rather than replacing its files.
+pub fn find_last_numbered_file(base_path:&Path, prefix:&str, suffix:&str) -> Result<Option<u32>, Box<dyn Error>> {
+    if !base_path.exists() {
+        return Ok(None);
+    }
+    let mut last:Option<u32> = None;
+    for entry in read_dir(base_path)? {
+        let name = entry?.file_name();
+        let idx = name.to_str()
+            .and_then(|n| n.strip_prefix(prefix))
+            .and_then(|n| n.strip_suffix(suffix))
+            .and_then(|n| n.parse::<u32>().ok());
+        if let Some(i) = idx {
+            last = Some(last.map_or(i, |l| l.max(i)));
+        }
+    }
+    Ok(last)
+}
+
+/// Builds the file store selected on the commandline.
+/// `liveblogs_per_archive` of zero means everything goes into a single archive, and `gzip_files` only applies to loose files.
+pub fn make_file_store(base_path:&str, format:ArchiveFormat, liveblogs_per_archive:usize, gzip_files:bool) -> Box<dyn FileStore> {
+    match format {
+        ArchiveFormat::None=>Box::new(LooseFiles::new(base_path, gzip_files)),
+        _=>Box::new(TarArchive::new(base_path, format, liveblogs_per_archive)),
+    }
+}
+
+/// Writes each entry as a file under the base path, optionally gzipping each one and adding `.gz` to its name.
+/// Files are written to a temporary name alongside the real one, synced to disk and then renamed into place, so that
+/// a crash or full disk never leaves a truncated file behind under the real name.
+pub struct LooseFiles {
+    base_path: PathBuf,
+    gzip: bool,
+    written: BTreeSet<PathBuf>,
+}
+
+impl LooseFiles {
+    pub fn new(base_path:&str, gzip:bool) -> LooseFiles {
+        LooseFiles { base_path: PathBuf::from(base_path), gzip, written: BTreeSet::new() }
+    }
+
+    fn file_name(&self, path:&str) -> PathBuf {
+        let mut file_name = self.base_path.join(path);
+        if self.gzip {
+            file_name.as_mut_os_string().push(".gz");
+        }
+        file_name
+    }
+}
+
+/// Writes `content` to `file_name` via a temporary file in the same directory, which is synced and then renamed over
+/// the target. The directory is synced as well (where the platform allows) so that the rename itself is durable.
+pub fn write_file_atomically(file_name:&Path, content:&[u8]) -> Result<(), Box<dyn Error>> {
+    let parent = file_name.parent().ok_or_else(|| format!("{} has no parent directory", file_name.display()))?;
+    create_dir_all(parent).map_err(|e| format!("unable to create {}: {}", parent.display(), e))?;
+
+    let base_name = file_name.file_name().and_then(|n| n.to_str()).ok_or_else(|| format!("{} is not a valid file name", file_name.display()))?;
+    let temp_name = parent.join(format!(".{}.{}.tmp", base_name, std::process::id()));
+
+    let result = File::create(&temp_name)
+        .and_then(|mut f| {
+            f.write_all(content)?;
+            f.sync_all()
+        })
+        .and_then(|_| rename(&temp_name, file_name));
+
+    if let Err(e) = result {
+        let _ = remove_file(&temp_name);
+        return Err(format!("could not write {}: {}", file_name.display(), e).into());
+    }
+
+    #[cfg(unix)]
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+impl FileStore for LooseFiles {
+    fn write_entry(&mut self, path:&str, content:&[u8]) -> Result<(), Box<dyn Error>> {
+        let file_name = self.file_name(path);
+        if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(content)?;
+            write_file_atomically(&file_name, &encoder.finish()?)?;
+        } else {
+            write_file_atomically(&file_name, content)?;
+        }
+        self.written.insert(file_name);
+        Ok(())
+    }
+
+    fn remove_entry(&mut self, path:&str) -> Result<(), Box<dyn Error>> {
+        let file_name = self.file_name(path);
+        self.written.remove(&file_name);
+        match remove_file(file_name) {
+            Ok(_)=>Ok(()),
+            Err(e) if e.kind()==std::io::ErrorKind::NotFound=>Ok(()),
+            Err(e)=>Err(Box::new(e)),
+        }
+    }
+
+    fn read_entry(&self, path:&str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let file = match File::open(self.file_name(path)) {
+            Ok(f)=>f,
+            Err(e) if e.kind()==std::io::ErrorKind::NotFound=>return Ok(None),
+            Err(e)=>return Err(Box::new(e)),
+        };
+        let mut content:Vec<u8> = vec!();
+        if self.gzip {
+            flate2::read::GzDecoder::new(file).read_to_end(&mut content)?;
+        } else {
+            std::io::BufReader::new(file).read_to_end(&mut content)?;
+        }
+        Ok(Some(content))
+    }
+
+    fn liveblog_finished(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        self.written.iter().cloned().collect()
+    }
+}
+
+/// The compressed stream underneath a tar archive. This is an enum rather than a boxed Write so that we can
+/// finish the compression properly and see any errors from doing so.
+enum ArchiveEncoder {
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Write for ArchiveEncoder {
+    fn write(&mut self, buf:&[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveEncoder::Gzip(e)=>e.write(buf),
+            ArchiveEncoder::Zstd(e)=>e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveEncoder::Gzip(e)=>e.flush(),
+            ArchiveEncoder::Zstd(e)=>e.flush(),
+        }
+    }
+}
+
+impl ArchiveEncoder {
+    /// Finishes the compressed stream and syncs the underlying file to disk
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        let inner = match self {
+            ArchiveEncoder::Gzip(e)=>e.finish()?,
+            ArchiveEncoder::Zstd(e)=>e.finish()?,
+        };
+        inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+}
+
+/// Streams entries into compressed tar archives, `liveblogs-00000.tar.zst` etc. under the base path.
+/// A new archive is started after every `liveblogs_per_archive` liveblogs (never, if that is zero) and each
+/// archive ends with a `manifest.json` listing the entries in it. Numbering carries on from any archives already in
+/// the base path, so an earlier harvest's archives are never replaced.
+/// Like loose files, each archive is written under a temporary name and only renamed into place once it is complete.
+pub struct TarArchive {
+    base_path: PathBuf,
+    format: ArchiveFormat,
+    liveblogs_per_archive: usize,
+    archive_index: u32,
+    liveblogs_in_archive: usize,
+    builder: Option<tar::Builder<ArchiveEncoder>>,
+    manifest: Vec<ManifestEntry>,
+    written: Vec<PathBuf>,
+}
+
+impl TarArchive {
+    pub fn new(base_path:&str, format:ArchiveFormat, liveblogs_per_archive:usize) -> TarArchive {
+        TarArchive {
+            base_path: PathBuf::from(base_path),
+            format,
+            liveblogs_per_archive,
+            archive_index: 0,
+            liveblogs_in_archive: 0,
+            builder: None,
+            manifest: vec!(),
+            written: vec!(),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            ArchiveFormat::TarZst=>".tar.zst",
+            _=>".tar.gz",
+        }
+    }
+
+    fn archive_name(&self) -> String {
+        format!("liveblogs-{:05}{}", self.archive_index, self.extension())
+    }
+
+    fn temp_name(&self) -> PathBuf {
+        self.base_path.join(format!(".{}.{}.tmp", self.archive_name(), std::process::id()))
+    }
+
+    fn open_archive(&mut self) -> Result<&mut tar::Builder<ArchiveEncoder>, Box<dyn Error>> {
+        if self.builder.is_none() {
+            create_dir_all(&self.base_path)?;
+            if self.written.is_empty() {
+                self.archive_index = find_last_numbered_file(&self.base_path, "liveblogs-", self.extension())?.map_or(0, |i| i + 1);
+            }
+            eprintln!("DEBUG writing archive {}", self.base_path.join(self.archive_name()).display());
+            let file = BufWriter::new(File::create(self.temp_name())?);
+            let encoder = match self.format {
+                ArchiveFormat::TarZst=>ArchiveEncoder::Zstd(zstd::Encoder::new(file, 0)?),
+                _=>ArchiveEncoder::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            };
+            self.builder = Some(tar::Builder::new(encoder));
+        }
+        self.builder.as_mut().ok_or_else(|| "could not open archive".into())
+    }
+
+    fn append(builder:&mut tar::Builder<ArchiveEncoder>, path:&str, content:&[u8]) -> Result<(), Box<dyn Error>> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs());
+        header.set_cksum();
+        builder.append_data(&mut header, Path::new(path), content)?;
+        Ok(())
+    }
+
+    /// Adds the manifest to the current archive and finishes it
+    fn close_archive(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(mut builder) = self.builder.take() {
+            let manifest = serde_json::to_vec(&self.manifest)?;
+            TarArchive::append(&mut builder, "manifest.json", &manifest)?;
+            builder.into_inner()?.finish()?;
+            let archive_name = self.base_path.join(self.archive_name());
+            rename(self.temp_name(), &archive_name)?;
+            self.written.push(archive_name);
+            self.manifest.clear();
+            self.archive_index += 1;
+            self.liveblogs_in_archive = 0;
+        }
+        Ok(())
+    }
+}
+
+impl FileStore for TarArchive {
+    fn write_entry(&mut self, path:&str, content:&[u8]) -> Result<(), Box<dyn Error>> {
+        let builder = self.open_archive()?;
+        TarArchive::append(builder, path, content)?;
+        self.manifest.push(ManifestEntry { path: path.to_owned(), size: content.len() as u64 });
+        Ok(())
+    }
+
+    fn remove_entry(&mut self, _path:&str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn read_entry(&self, _path:&str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    fn liveblog_finished(&mut self) -> Result<(), Box<dyn Error>> {
+        self.liveblogs_in_archive += 1;
+        if self.liveblogs_per_archive > 0 && self.liveblogs_in_archive >= self.liveblogs_per_archive {
+            self.close_archive()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.close_archive()
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        self.written.clone()
+    }
+
+    /// Entries only appear once the archive they are in is finished and renamed into place
+    fn writes_through(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Reads back every entry of a compressed tar as (path, content)
+    fn read_archive(path:&Path) -> Vec<(String, String)> {
+        let file = File::open(path).unwrap();
+        let decoder:Box<dyn Read> = if path.to_str().unwrap().ends_with(".zst") {
+            Box::new(zstd::Decoder::new(file).unwrap())
+        } else {
+            Box::new(flate2::read::GzDecoder::new(file))
+        };
+        let mut archive = tar::Archive::new(decoder);
+        archive.entries().unwrap().map(|e| {
+            let mut entry = e.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_owned();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            (path, content)
+        }).collect()
+    }
+
+    #[test]
+    pub fn test_loose_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = make_file_store(dir.path().to_str().unwrap(), ArchiveFormat::None, 0, false);
+        store.write_entry("blog/HEAD.json", b"{}").unwrap();
+        store.close().unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.path().join("blog/HEAD.json")).unwrap(), "{}");
+    }
+
+    #[test]
+    pub fn test_loose_files_replaced_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = make_file_store(dir.path().to_str().unwrap(), ArchiveFormat::None, 0, false);
+        store.write_entry("blog/HEAD.json", b"{\"version\":1}").unwrap();
+        store.write_entry("blog/HEAD.json", b"{\"version\":2}").unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.path().join("blog/HEAD.json")).unwrap(), "{\"version\":2}");
+        //no temporary files should be left lying around
+        let names:Vec<String> = std::fs::read_dir(dir.path().join("blog")).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        assert_eq!(names, vec!["HEAD.json"]);
+
+        assert_eq!(store.read_entry("blog/HEAD.json").unwrap().unwrap(), b"{\"version\":2}");
+        store.remove_entry("blog/HEAD.json").unwrap();
+        assert!(!dir.path().join("blog/HEAD.json").exists());
+        assert!(store.read_entry("blog/HEAD.json").unwrap().is_none());
+        //removing something that is not there is fine
+        store.remove_entry("blog/HEAD.json").unwrap();
+    }
+
+    #[test]
+    pub fn test_loose_files_unwritable_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("blog"), "not a directory").unwrap();
+        let mut store = make_file_store(dir.path().to_str().unwrap(), ArchiveFormat::None, 0, false);
+
+        let result = store.write_entry("blog/HEAD.json", b"{}");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    pub fn test_gzipped_loose_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = make_file_store(dir.path().to_str().unwrap(), ArchiveFormat::None, 0, true);
+        store.write_entry("blog/HEAD.json", b"{\"hello\":true}").unwrap();
+        store.close().unwrap();
+
+        assert!(!dir.path().join("blog/HEAD.json").exists());
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(File::open(dir.path().join("blog/HEAD.json.gz")).unwrap()).read_to_string(&mut content).unwrap();
+        assert_eq!(content, "{\"hello\":true}");
+        assert_eq!(store.read_entry("blog/HEAD.json").unwrap().unwrap(), b"{\"hello\":true}");
+    }
+
+    #[test]
+    pub fn test_single_tar_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = make_file_store(dir.path().to_str().unwrap(), ArchiveFormat::TarGz, 0, false);
+        for blog in ["first", "second", "third"] {
+            store.write_entry(&format!("{}/HEAD.json", blog), b"{}").unwrap();
+            store.write_entry(&format!("{}/META.json", blog), b"{\"meta\":1}").unwrap();
+            store.liveblog_finished().unwrap();
+        }
+        store.close().unwrap();
+
+        assert!(!dir.path().join("liveblogs-00001.tar.gz").exists());
+        let entries = read_archive(&dir.path().join("liveblogs-00000.tar.gz"));
+        assert_eq!(entries.len(), 7);
+        assert_eq!(entries[1], ("first/META.json".to_owned(), "{\"meta\":1}".to_owned()));
+
+        let (manifest_path, manifest) = entries.last().unwrap();
+        assert_eq!(manifest_path, "manifest.json");
+        let manifest:serde_json::Value = serde_json::from_str(manifest).unwrap();
+        assert_eq!(manifest.as_array().unwrap().len(), 6);
+        assert_eq!(manifest[5]["path"], "third/META.json");
+        assert_eq!(manifest[5]["size"], 10);
+    }
+
+    #[test]
+    pub fn test_tar_zst_per_n_liveblogs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = make_file_store(dir.path().to_str().unwrap(), ArchiveFormat::TarZst, 2, false);
+        for blog in ["first", "second", "third"] {
+            store.write_entry(&format!("{}/HEAD.json", blog), b"{}").unwrap();
+            store.liveblog_finished().unwrap();
+        }
+        store.close().unwrap();
+
+        let first = read_archive(&dir.path().join("liveblogs-00000.tar.zst"));
+        assert_eq!(first.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>(), vec!["first/HEAD.json", "second/HEAD.json", "manifest.json"]);
+        let second = read_archive(&dir.path().join("liveblogs-00001.tar.zst"));
+        assert_eq!(second.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>(), vec!["third/HEAD.json", "manifest.json"]);
+        assert!(!dir.path().join("liveblogs-00002.tar.zst").exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    pub fn test_tar_continues_numbering() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("liveblogs-00003.tar.gz"), "earlier harvest").unwrap();
+        let mut store = make_file_store(dir.path().to_str().unwrap(), ArchiveFormat::TarGz, 1, false);
+        for blog in ["first", "second"] {
+            store.write_entry(&format!("{}/HEAD.json", blog), b"{}").unwrap();
+            store.liveblog_finished().unwrap();
+        }
+        store.close().unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.path().join("liveblogs-00003.tar.gz")).unwrap(), "earlier harvest");
+        assert_eq!(read_archive(&dir.path().join("liveblogs-00004.tar.gz"))[0].0, "first/HEAD.json");
+        assert_eq!(read_archive(&dir.path().join("liveblogs-00005.tar.gz"))[0].0, "second/HEAD.json");
+        assert_eq!(store.output_files(), vec!(dir.path().join("liveblogs-00004.tar.gz"), dir.path().join("liveblogs-00005.tar.gz")));
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::models::{CapiBlock, CapiBlockAttributes, CapiBlocksContainer, CapiElement};
+
+/// Name of the index of harvested liveblogs, kept at the top of the output path across runs
+pub const HARVEST_INDEX_FILE:&str = "HARVESTED.jsonl";
+
+/// What to do with a liveblog that an earlier harvest has already written to the output path
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OverwritePolicy {
+    /// Write it again, replacing the earlier copy. Only directories without archives and SQLite can do this
+    Overwrite,
+    /// Keep the earlier copy and do not write this one
+    Skip,
+    /// Write it again, replacing the earlier copy, only if its content has changed since it was last harvested
+    IfChanged,
+    /// Write the whole run into a new subdirectory of the output path, named after the time it started
+    Versioned,
+}
+
+/// One line of the harvest index
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HarvestIndexEntry {
+    pub capi_id: String,
+    pub content_hash: String,
+    pub retrieved_at: DateTime<Utc>,
+    /// The subdirectory the liveblog was written to, for versioned runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// Keeps track of which liveblogs have been written to an output path, and a hash of their content when they were,
+/// so that the overwrite policy works the same way whichever output format is used.
+/// New entries are only added to the file by `save`, once the liveblog is safely written: straight after each one for
+/// sinks that write through to their final files, otherwise once the run's output has been flushed. That way a
+/// liveblog is never recorded as harvested if the run fails before it is on disk.
+pub struct HarvestIndex {
+    file_name: PathBuf,
+    hashes: HashMap<String, String>,
+    pending: Vec<HarvestIndexEntry>,
+}
+
+impl HarvestIndex {
+    /// Reads the index under the given output path; if there isn't one yet then the index starts empty
+    pub fn load(base_path:&Path) -> Result<HarvestIndex, Box<dyn Error>> {
+        let file_name = base_path.join(HARVEST_INDEX_FILE);
+        let mut hashes = HashMap::new();
+
+        if file_name.exists() {
+            let reader = BufReader::new(File::open(&file_name)?);
+            for (line_no, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<HarvestIndexEntry>(&line) {
+                    //later lines are later harvests, so they replace earlier ones
+                    Ok(entry)=>{ hashes.insert(entry.capi_id, entry.content_hash); },
+                    Err(e)=>eprintln!("WARNING ignoring line {} of {}: {}", line_no + 1, file_name.display(), e),
+                }
+            }
+        }
+
+        Ok(HarvestIndex { file_name, hashes, pending: vec!() })
+    }
+
+    pub fn file_name(&self) -> &Path {
+        &self.file_name
+    }
+
+    /// Decides whether a liveblog should be written under the given policy
+    pub fn should_write(&self, policy:OverwritePolicy, capi_id:&str, content_hash:&str) -> bool {
+        match (policy, self.hashes.get(capi_id)) {
+            (OverwritePolicy::Overwrite, _) | (OverwritePolicy::Versioned, _)=>true,
+            (_, None)=>true,
+            (OverwritePolicy::Skip, Some(_))=>false,
+            (OverwritePolicy::IfChanged, Some(previous))=>previous!=content_hash,
+        }
+    }
+
+    /// Notes that a liveblog has been written in this run
+    pub fn record(&mut self, entry:HarvestIndexEntry) {
+        self.hashes.insert(entry.capi_id.to_owned(), entry.content_hash.to_owned());
+        self.pending.push(entry);
+    }
+
+    /// Appends everything recorded since the last save to the index file
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(parent) = self.file_name.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.file_name)?;
+        let mut content:Vec<u8> = vec!();
+        for entry in self.pending.iter() {
+            serde_json::to_writer(&mut content, entry)?;
+            content.push(b'\n');
+        }
+        file.write_all(&content)?;
+        file.sync_all()?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// The fields of a block exactly as CAPI sent them. Anything we work out from them, like `bodyText`, is left out so
+/// that a change to how it is worked out doesn't make every liveblog look as if its content had changed.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HashedBlock<'a> {
+    id:&'a str,
+    body_html:&'a str,
+    attributes:&'a CapiBlockAttributes,
+    first_published_date:&'a Option<String>,
+}
+
+impl<'a> HashedBlock<'a> {
+    fn new(block:&'a CapiBlock) -> HashedBlock<'a> {
+        HashedBlock {
+            id: &block.id,
+            body_html: &block.bodyHtml,
+            attributes: &block.attributes,
+            first_published_date: &block.firstPublishedDate,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HashedContent<'a> {
+    main:HashedBlock<'a>,
+    main_elements:&'a Vec<CapiElement>,
+    body:Vec<HashedBlock<'a>>,
+}
+
+impl<'a> HashedContent<'a> {
+    fn new(blocks:&'a CapiBlocksContainer) -> HashedContent<'a> {
+        HashedContent {
+            main: HashedBlock::new(&blocks.main.block),
+            main_elements: &blocks.main.elements,
+            body: blocks.body.iter().map(HashedBlock::new).collect(),
+        }
+    }
+}
+
+/// Returns a hex-encoded SHA-256 of a liveblog's blocks, which changes whenever any of the content CAPI sent does
+pub fn content_hash(blocks:&CapiBlocksContainer) -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    serde_json::to_writer(&mut hasher, &HashedContent::new(blocks))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The name of the subdirectory that a versioned run started at the given time writes to
+pub fn version_name(started_at:&DateTime<Utc>) -> String {
+    started_at.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::block;
+
+    fn make_entry(capi_id:&str, content_hash:&str) -> HarvestIndexEntry {
+        HarvestIndexEntry {
+            capi_id: capi_id.to_owned(),
+            content_hash: content_hash.to_owned(),
+            retrieved_at: Utc::now(),
+            version: None,
+        }
+    }
+
+    fn make_container(body_html:&str) -> CapiBlocksContainer {
+        CapiBlocksContainer { main: block("block").html(body_html).main(), body: vec!(block("block").html(body_html).build()) }
+    }
+
+    #[test]
+    pub fn test_should_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = HarvestIndex::load(dir.path()).unwrap();
+        index.record(make_entry("news/live/blog", "abc"));
+
+        for policy in [OverwritePolicy::Overwrite, OverwritePolicy::Skip, OverwritePolicy::IfChanged, OverwritePolicy::Versioned] {
+            assert!(index.should_write(policy, "news/live/other", "abc"));
+        }
+        assert!(index.should_write(OverwritePolicy::Overwrite, "news/live/blog", "abc"));
+        assert!(index.should_write(OverwritePolicy::Versioned, "news/live/blog", "abc"));
+        assert!(!index.should_write(OverwritePolicy::Skip, "news/live/blog", "def"));
+        assert!(!index.should_write(OverwritePolicy::IfChanged, "news/live/blog", "abc"));
+        assert!(index.should_write(OverwritePolicy::IfChanged, "news/live/blog", "def"));
+    }
+
+    #[test]
+    pub fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut index = HarvestIndex::load(dir.path()).unwrap();
+            index.record(make_entry("news/live/blog", "abc"));
+            index.record(make_entry("news/live/other", "123"));
+            index.save().unwrap();
+        }
+        {
+            let mut index = HarvestIndex::load(dir.path()).unwrap();
+            index.record(make_entry("news/live/blog", "def"));
+            //nothing is written until save is called
+            assert_eq!(std::fs::read_to_string(dir.path().join(HARVEST_INDEX_FILE)).unwrap().lines().count(), 2);
+            index.save().unwrap();
+        }
+        std::fs::OpenOptions::new().append(true).open(dir.path().join(HARVEST_INDEX_FILE)).unwrap().write_all(b"not json\n").unwrap();
+
+        let index = HarvestIndex::load(dir.path()).unwrap();
+        assert!(!index.should_write(OverwritePolicy::IfChanged, "news/live/blog", "def"));
+        assert!(index.should_write(OverwritePolicy::IfChanged, "news/live/blog", "abc"));
+        assert!(!index.should_write(OverwritePolicy::IfChanged, "news/live/other", "123"));
+    }
+
+    #[test]
+    pub fn test_content_hash() {
+        let first = content_hash(&make_container("<p>Hello</p>")).unwrap();
+        assert_eq!(first.len(), 64);
+        assert_eq!(first, content_hash(&make_container("<p>Hello</p>")).unwrap());
+        assert_ne!(first, content_hash(&make_container("<p>Hello again</p>")).unwrap());
+    }
+
+    #[test]
+    pub fn test_content_hash_ignores_body_text() {
+        let container = make_container("<p>Hello</p>");
+        //the text we work out from the HTML is part of the output, but not of what is hashed
+        assert!(serde_json::to_string(&container).unwrap().contains("bodyText"));
+        let hashed = serde_json::to_string(&HashedContent::new(&container)).unwrap();
+        assert!(!hashed.contains("bodyText"));
+        assert!(hashed.contains("\"bodyHtml\":\"<p>Hello</p>\""));
+
+        let mut hasher = Sha256::new();
+        hasher.update(hashed.as_bytes());
+        let expected:String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(content_hash(&container).unwrap(), expected);
+    }
+
+    #[test]
+    pub fn test_version_name() {
+        let started_at = DateTime::parse_from_rfc3339("2023-10-13T09:08:07+01:00").unwrap().with_timezone(&Utc);
+        assert_eq!(version_name(&started_at), "20231013T080807Z");
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+use crate::models::*;
+use crate::writer::OutputSink;
+use crate::file_store::find_last_numbered_file;
+use crate::s3::{S3Client, S3Upload};
+use crate::projection::{Projection, ProjectedSegment, ProjectedStats};
+use crate::training::{PairOptions, TrainingPair};
+use crate::manifest::ManifestFile;
+
+/// One line of a JSON Lines shard: a single segment, with the details of the liveblog it came from denormalised in
+/// so that every line can be used on its own.
+#[derive(Debug, Serialize)]
+pub struct JsonlRecord<'a> {
+    pub liveblog_id: &'a str,
+    pub segment_index: usize,
+    pub web_publication_date: DateTime<FixedOffset>,
+    pub retrieved_at: DateTime<FixedOffset>,
+    pub keyword_tags: &'a [CapiTag],
+    #[serde(flatten)]
+    pub segment: ProjectedSegment<'a>,
+}
+
+/// One line of the stdout stream, tagged with a `record_type` of `segment` or `stats` so a consumer can tell them apart
+#[derive(Debug, Serialize)]
+#[serde(tag = "record_type", rename_all = "snake_case")]
+pub enum StreamRecord<'a> {
+    Segment(JsonlRecord<'a>),
+    Stats(ProjectedStats<'a>),
+}
+
+/// Appends segments as JSON Lines to a series of shard files, e.g. `segments-00000.jsonl`, `segments-00001.jsonl` etc.
+/// A new shard is started once writing the next record would take the current one over `max_shard_bytes`, so a shard
+/// only exceeds the limit if it holds a single record bigger than that.
+/// If shards already exist in the output directory then we carry on appending to the last one.
+/// Shards can be uploaded to S3 instead, in which case they are named after the run, `segments-{run}-00000.jsonl`, as
+/// objects cannot be appended to.
+/// With `with_training_pairs` each line is a `TrainingPair` rather than a segment, and segments that do not make a pair
+/// are left out.
+pub struct JsonlSink {
+    target: ShardTarget,
+    file_prefix: String,
+    max_shard_bytes: u64,
+    shard_index: u32,
+    current: Option<ShardOutput>,
+    current_size: u64,
+    liveblog: Option<LiveblogDetails>,
+    written: BTreeSet<PathBuf>,
+    uploaded: Vec<ManifestFile>,
+    projection: Projection,
+    pairs: Option<PairOptions>,
+}
+
+/// The details of the liveblog being written that are copied into each record
+struct LiveblogDetails {
+    liveblog_id: String,
+    web_publication_date: DateTime<FixedOffset>,
+    retrieved_at: DateTime<FixedOffset>,
+    keyword_tags: Vec<CapiTag>,
+}
+
+impl LiveblogDetails {
+    fn from_stats(stats:&Stats) -> LiveblogDetails {
+        LiveblogDetails {
+            liveblog_id: stats.original_id.to_owned(),
+            web_publication_date: stats.web_publication_date,
+            retrieved_at: stats.retrieved_at,
+            keyword_tags: stats.keyword_tags.clone(),
+        }
+    }
+
+    fn record<'a>(&'a self, index:usize, segment:&'a SummarisedContent<'a>, projection:&'a Projection) -> JsonlRecord<'a> {
+        JsonlRecord {
+            liveblog_id: &self.liveblog_id,
+            segment_index: index,
+            web_publication_date: self.web_publication_date,
+            retrieved_at: self.retrieved_at,
+            keyword_tags: &self.keyword_tags,
+            segment: projection.segment(segment),
+        }
+    }
+}
+
+fn shard_file_name(file_prefix:&str, index:u32) -> String {
+    format!("{}-{:05}.jsonl", file_prefix, index)
+}
+
+/// Where the shards are written
+enum ShardTarget {
+    Local(PathBuf),
+    S3 { client: Arc<S3Client>, run_name: String },
+}
+
+/// The shard currently being written. Like `ArchiveEncoder` this is an enum so that finishing it can return errors.
+enum ShardOutput {
+    File(BufWriter<File>),
+    S3(S3Upload),
+}
+
+impl ShardOutput {
+    fn write_all(&mut self, content:&[u8]) -> Result<(), Box<dyn Error>> {
+        match self {
+            ShardOutput::File(f)=>f.write_all(content)?,
+            ShardOutput::S3(u)=>u.write_all(content)?,
+        }
+        Ok(())
+    }
+
+    /// Pushes anything buffered out to the file. Parts of an S3 upload are only sent once they are full.
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if let ShardOutput::File(f) = self {
+            f.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the shard, returning its manifest entry if it was uploaded rather than written to disk
+    fn finish(self) -> Result<Option<ManifestFile>, Box<dyn Error>> {
+        match self {
+            ShardOutput::File(mut f)=>{
+                f.flush()?;
+                Ok(None)
+            },
+            ShardOutput::S3(u)=>Ok(Some(u.finish()?)),
+        }
+    }
+}
+
+impl JsonlSink {
+    /// Writes shards named `{file_prefix}-00000.jsonl` etc. under the given path
+    pub fn new(base_path:&str, file_prefix:&str, max_shard_bytes:u64) -> Result<JsonlSink, Box<dyn Error>> {
+        let base_path = PathBuf::from(base_path);
+        create_dir_all(&base_path)?;
+        let shard_index = find_last_numbered_file(&base_path, &format!("{}-", file_prefix), ".jsonl")?.unwrap_or(0);
+
+        Ok(JsonlSink {
+            target: ShardTarget::Local(base_path),
+            file_prefix: file_prefix.to_owned(),
+            max_shard_bytes,
+            shard_index,
+            current: None,
+            current_size: 0,
+            liveblog: None,
+            written: BTreeSet::new(),
+            uploaded: vec!(),
+            projection: Projection::default(),
+            pairs: None,
+        })
+    }
+
+    /// Uploads the shards to S3 under the client's prefix. `run_name` keeps them apart from earlier runs' shards.
+    pub fn new_s3(client:Arc<S3Client>, file_prefix:&str, max_shard_bytes:u64, run_name:&str) -> JsonlSink {
+        JsonlSink {
+            target: ShardTarget::S3 { client, run_name: run_name.to_owned() },
+            file_prefix: file_prefix.to_owned(),
+            max_shard_bytes,
+            shard_index: 0,
+            current: None,
+            current_size: 0,
+            liveblog: None,
+            written: BTreeSet::new(),
+            uploaded: vec!(),
+            projection: Projection::default(),
+            pairs: None,
+        }
+    }
+
+    /// Writes only the fields picked by the given projection into each record
+    pub fn with_projection(mut self, projection:Projection) -> JsonlSink {
+        self.projection = projection;
+        self
+    }
+
+    /// Writes a training pair for each segment that makes one, instead of the segment itself
+    pub fn with_training_pairs(mut self, options:PairOptions) -> JsonlSink {
+        self.pairs = Some(options);
+        self
+    }
+
+    fn open_shard(&mut self) -> Result<(), Box<dyn Error>> {
+        match &self.target {
+            ShardTarget::Local(base_path)=>{
+                let file_name = base_path.join(shard_file_name(&self.file_prefix, self.shard_index));
+                let file = OpenOptions::new().create(true).append(true).open(&file_name)?;
+                self.current_size = file.metadata()?.len();
+                self.current = Some(ShardOutput::File(BufWriter::new(file)));
+                eprintln!("DEBUG writing segments to {}", file_name.display());
+                self.written.insert(file_name);
+            },
+            ShardTarget::S3 { client, run_name }=>{
+                let key = client.key(&format!("{}-{}-{:05}.jsonl", self.file_prefix, run_name, self.shard_index));
+                eprintln!("DEBUG uploading segments to {}", key);
+                self.current_size = 0;
+                self.current = Some(ShardOutput::S3(S3Upload::new(client.clone(), key)));
+            }
+        }
+        Ok(())
+    }
+
+    fn close_shard(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(shard) = self.current.take() {
+            if let Some(uploaded) = shard.finish()? {
+                self.uploaded.push(uploaded);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line:&[u8]) -> Result<(), Box<dyn Error>> {
+        if self.current.is_none() {
+            self.open_shard()?;
+        }
+
+        let line_size = line.len() as u64 + 1;
+        if self.current_size > 0 && self.current_size + line_size > self.max_shard_bytes {
+            self.close_shard()?;
+            self.shard_index += 1;
+            self.open_shard()?;
+        }
+
+        if let Some(shard) = self.current.as_mut() {
+            shard.write_all(line)?;
+            shard.write_all(b"\n")?;
+        }
+        self.current_size += line_size;
+        Ok(())
+    }
+}
+
+impl OutputSink for JsonlSink {
+    fn begin_liveblog(&mut self, _main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        self.liveblog = Some(LiveblogDetails::from_stats(stats));
+        Ok(())
+    }
+
+    /// Appends one record for the segment, with the details of the current liveblog
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+        let details = self.liveblog.as_ref().ok_or("write_segment called before begin_liveblog")?;
+        let line = match &self.pairs {
+            None=>serde_json::to_vec(&details.record(index, segment, &self.projection))?,
+            Some(options)=>match TrainingPair::from_segment(&details.liveblog_id, details.web_publication_date, index, segment, options) {
+                Some(pair)=>serde_json::to_vec(&pair)?,
+                None=>return Ok(()),
+            },
+        };
+        self.write_line(&line)
+    }
+
+    /// The stats are already denormalised into every record, so there is nothing more to write
+    fn write_stats(&mut self, _stats:&Stats) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.liveblog = None;
+        if let Some(shard) = self.current.as_mut() {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        //an S3 shard cannot be added to once it is finished, so anything written after this goes into the next one
+        if self.current.is_some() && matches!(self.target, ShardTarget::S3 { .. }) {
+            self.close_shard()?;
+            self.shard_index += 1;
+            Ok(())
+        } else {
+            self.close_shard()
+        }
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        self.written.iter().cloned().collect()
+    }
+
+    fn uploaded_objects(&self) -> Vec<ManifestFile> {
+        self.uploaded.clone()
+    }
+
+    /// Local shards are appended to as we go, but an S3 shard only appears once its upload is finished
+    fn writes_through(&self) -> bool {
+        matches!(self.target, ShardTarget::Local(_))
+    }
+}
+
+/// Streams segments and stats as JSON Lines to stdout (or any other writer), so the extractor can be used as one
+/// stage of a pipeline. Each liveblog's segments are followed by its stats, and the stream is flushed at the end of
+/// every liveblog so that whatever is reading it sees complete liveblogs as soon as they are ready.
+pub struct StreamSink<W:Write> {
+    out: W,
+    liveblog: Option<LiveblogDetails>,
+    projection: Projection,
+}
+
+impl<W:Write> StreamSink<W> {
+    pub fn new(out:W) -> StreamSink<W> {
+        StreamSink { out, liveblog: None, projection: Projection::default() }
+    }
+
+    /// Writes only the fields picked by the given projection into each record
+    pub fn with_projection(mut self, projection:Projection) -> StreamSink<W> {
+        self.projection = projection;
+        self
+    }
+}
+
+fn write_record(out:&mut dyn Write, record:&StreamRecord) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+impl<W:Write> OutputSink for StreamSink<W> {
+    fn begin_liveblog(&mut self, _main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        self.liveblog = Some(LiveblogDetails::from_stats(stats));
+        Ok(())
+    }
+
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+        let details = self.liveblog.as_ref().ok_or("write_segment called before begin_liveblog")?;
+        write_record(&mut self.out, &StreamRecord::Segment(details.record(index, segment, &self.projection)))
+    }
+
+    fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        write_record(&mut self.out, &StreamRecord::Stats(self.projection.stats(stats)))
+    }
+
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.liveblog = None;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Nothing is written to disk
+    fn output_files(&self) -> Vec<PathBuf> {
+        vec!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{block, keyword_tag, stats};
+    use crate::writer::write_liveblog;
+    use std::fs::read_to_string;
+
+    fn write_segments(sink:&mut JsonlSink, segments:&[SummarisedContent], stats:&Stats) {
+        let main = block("main").main();
+        write_liveblog(sink, segments, &MainContent { main: &main, standfirst: None }, stats).unwrap();
+        sink.flush().unwrap();
+    }
+
+    #[test]
+    pub fn test_write_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").build(), block("b").summary(true).build(), block("c").build()];
+        let segments = vec!(
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2])),
+            SummarisedContent::new(&blocks[1], vec!(&blocks[0])),
+        );
+
+        let mut writer = JsonlSink::new(dir.path().to_str().unwrap(), "segments", 1024*1024).unwrap();
+        write_segments(&mut writer, &segments, &stats("news/live/first").tags(vec!(keyword_tag("world/world"))).build());
+
+        let content = read_to_string(dir.path().join("segments-00000.jsonl")).unwrap();
+        let lines:Vec<serde_json::Value> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["liveblog_id"], "news/live/first");
+        assert_eq!(lines[0]["segment_index"], 0);
+        assert_eq!(lines[1]["segment_index"], 1);
+        assert_eq!(lines[1]["summary"]["id"], "b");
+        assert_eq!(lines[1]["events"][0]["id"], "a");
+        assert_eq!(lines[1]["keyword_tags"][0]["id"], "world/world");
+        assert_eq!(lines[1]["web_publication_date"], "2022-01-02T03:04:05Z");
+    }
+
+    #[test]
+    pub fn test_liveblog_written_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").summary(true).build(), block("b").build()];
+        let segments = vec!(SummarisedContent::new(&blocks[0], vec!(&blocks[1])));
+        let main = block("main").main();
+
+        //each finished liveblog is on disk before the sink is flushed, so the harvest index can record it
+        let mut writer = JsonlSink::new(dir.path().to_str().unwrap(), "segments", 1024*1024).unwrap();
+        assert!(writer.writes_through());
+        write_liveblog(&mut writer, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/first").tags(vec!(keyword_tag("world/world"))).build()).unwrap();
+        assert_eq!(read_to_string(dir.path().join("segments-00000.jsonl")).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    pub fn test_shard_rollover() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").summary(true).build(), block("b").build()];
+        let segments:Vec<SummarisedContent> = (0..10).map(|_| SummarisedContent::new(&blocks[0], vec!(&blocks[1]))).collect();
+        let stats = stats("news/live/first").tags(vec!(keyword_tag("world/world"))).build();
+        let record_size = serde_json::to_vec(&LiveblogDetails::from_stats(&stats).record(0, &segments[0], &Projection::default())).unwrap().len() as u64 + 1;
+
+        //room for three records per shard
+        let mut writer = JsonlSink::new(dir.path().to_str().unwrap(), "segments", record_size * 3 + 5).unwrap();
+        write_segments(&mut writer, &segments, &stats);
+
+        let line_counts:Vec<usize> = (0..4)
+            .map(|i| read_to_string(dir.path().join(shard_file_name("segments", i))).unwrap().lines().count())
+            .collect();
+        assert_eq!(line_counts, vec!(3, 3, 3, 1));
+        assert!(!dir.path().join(shard_file_name("segments", 4)).exists());
+    }
+
+    #[test]
+    pub fn test_continues_last_shard() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").summary(true).build()];
+        let segments = vec!(SummarisedContent::new(&blocks[0], vec!()));
+
+        {
+            let mut writer = JsonlSink::new(dir.path().to_str().unwrap(), "segments", 1024*1024).unwrap();
+            write_segments(&mut writer, &segments, &stats("news/live/first").tags(vec!(keyword_tag("world/world"))).build());
+        }
+        std::fs::write(dir.path().join(shard_file_name("segments", 3)), "").unwrap();
+
+        let mut writer = JsonlSink::new(dir.path().to_str().unwrap(), "segments", 1024*1024).unwrap();
+        write_segments(&mut writer, &segments, &stats("news/live/second").tags(vec!(keyword_tag("world/world"))).build());
+
+        assert_eq!(read_to_string(dir.path().join(shard_file_name("segments", 0))).unwrap().lines().count(), 1);
+        let content = read_to_string(dir.path().join(shard_file_name("segments", 3))).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(content.contains("news/live/second"));
+    }
+
+    #[test]
+    pub fn test_stream_records() {
+        let blocks = [block("a").build(), block("b").summary(true).build(), block("c").build()];
+        let segments = vec!(
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2])),
+            SummarisedContent::new(&blocks[1], vec!(&blocks[0])),
+        );
+        let main = block("main").main();
+        let mut out:Vec<u8> = vec!();
+        {
+            let mut sink = StreamSink::new(&mut out);
+            write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/first").tags(vec!(keyword_tag("world/world"))).build()).unwrap();
+            write_liveblog(&mut sink, &segments[..1], &MainContent { main: &main, standfirst: None }, &stats("news/live/second").tags(vec!(keyword_tag("world/world"))).build()).unwrap();
+            sink.flush().unwrap();
+        }
+
+        let lines:Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let types:Vec<&str> = lines.iter().map(|l| l["record_type"].as_str().unwrap()).collect();
+        assert_eq!(types, vec!("segment", "segment", "stats", "segment", "stats"));
+        assert_eq!(lines[1]["liveblog_id"], "news/live/first");
+        assert_eq!(lines[1]["segment_index"], 1);
+        assert_eq!(lines[1]["events"][0]["id"], "a");
+        assert_eq!(lines[2]["original_id"], "news/live/first");
+        assert_eq!(lines[2]["total_block_count"], 3);
+        assert_eq!(lines[3]["liveblog_id"], "news/live/second");
+    }
+
+    #[test]
+    pub fn test_write_training_pairs() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").build(), block("b").summary(true).build(), block("c").build()];
+        let segments = vec!(
+            SummarisedContent { summary: None, events: vec!(&blocks[0]), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() },
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2])),
+        );
+        let mut writer = JsonlSink::new(dir.path().to_str().unwrap(), "pairs", 1024*1024).unwrap().with_training_pairs(PairOptions::default());
+        write_segments(&mut writer, &segments, &stats("news/live/blog").tags(vec!(keyword_tag("world/world"))).build());
+
+        //the segment without a summary does not make a pair
+        let content = read_to_string(dir.path().join("pairs-00000.jsonl")).unwrap();
+        let lines:Vec<serde_json::Value> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["input"], "This is block c");
+        assert_eq!(lines[0]["target"], "This is block b");
+        assert_eq!(lines[0]["meta"]["liveblog_id"], "news/live/blog");
+        assert_eq!(lines[0]["meta"]["segment_index"], 1);
+        assert!(!dir.path().join("segments-00000.jsonl").exists());
+    }
+
+    #[test]
+    pub fn test_stream_projected_records() {
+        use crate::projection::{BlockField, StatsField};
+
+        let blocks = [block("a").build(), block("b").summary(true).build()];
+        let segments = vec!(SummarisedContent::new(&blocks[1], vec!(&blocks[0])));
+        let main = block("main").main();
+        let mut out:Vec<u8> = vec!();
+        {
+            let projection = Projection::new(&[BlockField::Id], &[StatsField::OriginalId]);
+            let mut sink = StreamSink::new(&mut out).with_projection(projection);
+            write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/first").tags(vec!(keyword_tag("world/world"))).build()).unwrap();
+        }
+
+        let lines:Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines[0]["liveblog_id"], "news/live/first");
+        assert_eq!(lines[0]["events"][0], serde_json::json!({"id": "a"}));
+        assert_eq!(lines[0]["summary"], serde_json::json!({"id": "b"}));
+        assert_eq!(lines[1], serde_json::json!({"record_type": "stats", "original_id": "news/live/first"}));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_upload_shards_to_s3() {
+        let server = httpmock::MockServer::start();
+        let first = server.mock(|when, then| {
+            when.method(httpmock::Method::PUT).path("/datasets/liveblogs/segments-20231013T080807Z-00000.jsonl").body_contains("news/live/first");
+            then.status(200);
+        });
+        let second = server.mock(|when, then| {
+            when.method(httpmock::Method::PUT).path("/datasets/liveblogs/segments-20231013T080807Z-00001.jsonl").body_contains("news/live/second");
+            then.status(200);
+        });
+        let blocks = [block("a").summary(true).build()];
+        let segments = vec!(SummarisedContent::new(&blocks[0], vec!()));
+
+        let mut writer = JsonlSink::new_s3(crate::s3::tests::make_client(&server.base_url(), 0), "segments", 1024*1024, "20231013T080807Z");
+        //flushing finishes the object, so the second liveblog goes into the next one
+        write_segments(&mut writer, &segments, &stats("news/live/first").tags(vec!(keyword_tag("world/world"))).build());
+        write_segments(&mut writer, &segments, &stats("news/live/second").tags(vec!(keyword_tag("world/world"))).build());
+
+        first.assert();
+        second.assert();
+        assert!(writer.output_files().is_empty());
+        let uploaded:Vec<String> = writer.uploaded_objects().into_iter().map(|o| o.path).collect();
+        assert_eq!(uploaded, vec!("s3://datasets/liveblogs/segments-20231013T080807Z-00000.jsonl", "s3://datasets/liveblogs/segments-20231013T080807Z-00001.jsonl"));
+    }
+}
@@ -0,0 +1,258 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::models::*;
+use crate::training::{pair_input, PairOptions};
+
+/// Counts the tokens in a piece of text. Different models split text differently, so this is only an estimate
+/// unless the counter matches the model's own tokenizer.
+pub trait TokenCounter {
+    fn count_tokens(&self, text:&str) -> usize;
+}
+
+/// The token counters that can be picked on the commandline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TokenizerName {
+    /// One token for each run of non-whitespace characters
+    Whitespace,
+    /// An estimate of a GPT-style byte-pair encoding (the default)
+    BpeEstimate,
+}
+
+/// Builds the token counter for the given name
+pub fn make_token_counter(name:TokenizerName) -> Box<dyn TokenCounter> {
+    match name {
+        TokenizerName::Whitespace=>Box::new(WhitespaceCounter {}),
+        TokenizerName::BpeEstimate=>Box::new(BpeEstimate {}),
+    }
+}
+
+pub struct WhitespaceCounter {}
+
+impl TokenCounter for WhitespaceCounter {
+    fn count_tokens(&self, text:&str) -> usize {
+        text.split_whitespace().count()
+    }
+}
+
+/// Estimates byte-pair encoding without a vocabulary: each punctuation mark or symbol is a token, and each run of
+/// letters or digits is a token for every four characters or part of four. Long words are usually a single token
+/// in a real vocabulary, so this tends to overestimate, which is the safe side when fitting into a context limit.
+pub struct BpeEstimate {}
+
+impl TokenCounter for BpeEstimate {
+    fn count_tokens(&self, text:&str) -> usize {
+        let mut tokens = 0;
+        let mut run:usize = 0;
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                run += 1;
+                continue;
+            }
+            tokens += run.div_ceil(4);
+            run = 0;
+            if !c.is_whitespace() {
+                tokens += 1;
+            }
+        }
+        tokens + run.div_ceil(4)
+    }
+}
+
+/// The size of a piece of text
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextLengths {
+    pub words: usize,
+    pub characters: usize,
+    pub tokens: usize,
+}
+
+impl TextLengths {
+    pub fn measure(text:&str, counter:&dyn TokenCounter) -> TextLengths {
+        TextLengths {
+            words: text.split_whitespace().count(),
+            characters: text.chars().count(),
+            tokens: counter.count_tokens(text),
+        }
+    }
+}
+
+/// The sizes of the plain text of a segment. `input` covers all of the events, separated by blank lines, unless the
+/// segment is being written as a training pair, when it is the pair's input with its separator, titles and
+/// timestamps. `summary` is zero if the segment has no summary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentLengths {
+    pub input: TextLengths,
+    pub summary: TextLengths,
+}
+
+impl SegmentLengths {
+    pub fn measure(segment:&SummarisedContent, counter:&dyn TokenCounter, pairs:Option<&PairOptions>) -> SegmentLengths {
+        let input = match pairs {
+            Some(options)=>pair_input(segment, options),
+            None=>segment.events.iter().map(|e| e.body_text()).collect::<Vec<_>>().join("\n\n"),
+        };
+        let summary = segment.summary.map(|s| s.body_text()).unwrap_or_default();
+        SegmentLengths {
+            input: TextLengths::measure(&input, counter),
+            summary: TextLengths::measure(&summary, counter),
+        }
+    }
+}
+
+/// Why a segment was dropped by a `LengthFilter`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthRejection {
+    InputTooShort,
+    InputTooLong,
+    SummaryTooShort,
+    SummaryTooLong,
+}
+
+/// Limits on the number of tokens in a segment's input and summary. A limit that is None is not checked.
+#[derive(Clone, Debug, Default)]
+pub struct LengthFilter {
+    pub min_input_tokens: Option<usize>,
+    pub max_input_tokens: Option<usize>,
+    pub min_summary_tokens: Option<usize>,
+    pub max_summary_tokens: Option<usize>,
+}
+
+impl LengthFilter {
+    pub fn is_empty(&self) -> bool {
+        self.min_input_tokens.is_none() && self.max_input_tokens.is_none() && self.min_summary_tokens.is_none() && self.max_summary_tokens.is_none()
+    }
+
+    /// Returns the first limit that the segment breaks, or None if it should be kept
+    pub fn check(&self, lengths:&SegmentLengths) -> Option<LengthRejection> {
+        let input = lengths.input.tokens;
+        let summary = lengths.summary.tokens;
+        if self.min_input_tokens.is_some_and(|min| input < min) {
+            Some(LengthRejection::InputTooShort)
+        } else if self.max_input_tokens.is_some_and(|max| input > max) {
+            Some(LengthRejection::InputTooLong)
+        } else if self.min_summary_tokens.is_some_and(|min| summary < min) {
+            Some(LengthRejection::SummaryTooShort)
+        } else if self.max_summary_tokens.is_some_and(|max| summary > max) {
+            Some(LengthRejection::SummaryTooLong)
+        } else {
+            None
+        }
+    }
+}
+
+/// How many segments the length filter dropped, by reason
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LengthFilterCounts {
+    pub input_too_short: usize,
+    pub input_too_long: usize,
+    pub summary_too_short: usize,
+    pub summary_too_long: usize,
+}
+
+impl LengthFilterCounts {
+    pub fn add(&mut self, rejection:LengthRejection) {
+        match rejection {
+            LengthRejection::InputTooShort=>self.input_too_short += 1,
+            LengthRejection::InputTooLong=>self.input_too_long += 1,
+            LengthRejection::SummaryTooShort=>self.summary_too_short += 1,
+            LengthRejection::SummaryTooLong=>self.summary_too_long += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.input_too_short + self.input_too_long + self.summary_too_short + self.summary_too_long
+    }
+}
+
+/// Measures a segment and stores its lengths on it, returning false if it breaks the filter's limits. The reason
+/// it should be dropped is added to `counts`. `pairs` is given when the segment is going to be a training pair.
+pub fn measure_and_check(segment:&mut SummarisedContent, counter:&dyn TokenCounter, pairs:Option<&PairOptions>, filter:&LengthFilter, counts:&mut LengthFilterCounts) -> bool {
+    let lengths = SegmentLengths::measure(segment, counter, pairs);
+    segment.lengths = Some(lengths);
+    match filter.check(&lengths) {
+        Some(rejection)=>{
+            counts.add(rejection);
+            false
+        },
+        None=>true,
+    }
+}
+
+/// Measures each segment and stores its lengths on it, then drops the segments that break the filter's limits.
+/// The reasons for dropping are added to `counts`.
+pub fn measure_and_filter(segments:&mut Vec<SummarisedContent>, counter:&dyn TokenCounter, pairs:Option<&PairOptions>, filter:&LengthFilter, counts:&mut LengthFilterCounts) {
+    segments.retain_mut(|segment| measure_and_check(segment, counter, pairs, filter, counts));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::block;
+
+    #[test]
+    pub fn test_token_counters() {
+        let text = "The Prime Minister's statement, delivered at 10am.";
+        assert_eq!(WhitespaceCounter {}.count_tokens(text), 7);
+        //The Prim|e Mini|ster ' s stat|emen|t , deli|vere|d at 10am .
+        assert_eq!(BpeEstimate {}.count_tokens(text), 17);
+        assert_eq!(BpeEstimate {}.count_tokens(""), 0);
+        assert_eq!(make_token_counter(TokenizerName::Whitespace).count_tokens("  two\nwords "), 2);
+    }
+
+    #[test]
+    pub fn test_measure_segment() {
+        let summary = block("summary").html("<p>Short <b>summary</b></p>").build();
+        let events = [block("a").html("<p>First event</p>").build(), block("b").html("<p>Second event here</p>").build()];
+        let segment = SummarisedContent::new(&summary, events.iter().collect());
+
+        let lengths = SegmentLengths::measure(&segment, &WhitespaceCounter {}, None);
+        assert_eq!(lengths.input, TextLengths { words: 5, characters: 30, tokens: 5 });
+        assert_eq!(lengths.summary, TextLengths { words: 2, characters: 13, tokens: 2 });
+
+        let no_summary = SummarisedContent { summary: None, events: events.iter().collect(), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() };
+        assert_eq!(SegmentLengths::measure(&no_summary, &WhitespaceCounter {}, None).summary, TextLengths::default());
+    }
+
+    #[test]
+    pub fn test_measure_training_pair() {
+        let summary = block("summary").html("<p>Short <b>summary</b></p>").build();
+        let mut titled = block("a").html("<p>First event</p>").build();
+        titled.attributes.title = Some("Breaking news".to_owned());
+        let events = [titled, block("b").html("<p>Second event here</p>").build()];
+        let segment = SummarisedContent::new(&summary, events.iter().collect());
+        let options = PairOptions { separator: " ### ".to_owned(), include_titles: true, ..PairOptions::default() };
+
+        //the separator and title are part of what the model sees, so they count towards the input
+        let pair = crate::training::TrainingPair::from_segment("news/live/blog", chrono::Utc::now().into(), 0, &segment, &options).unwrap();
+        let lengths = SegmentLengths::measure(&segment, &WhitespaceCounter {}, Some(&options));
+        assert_eq!(lengths.input, TextLengths::measure(&pair.input, &WhitespaceCounter {}));
+        assert_eq!(lengths.input.words, 8);
+        assert_eq!(lengths.summary, TextLengths::measure(&pair.target, &WhitespaceCounter {}));
+    }
+
+    #[test]
+    pub fn test_length_filter() {
+        let summary = block("summary").html("<p>A summary of four words</p>").build();
+        let short = [block("a").html("<p>Tiny</p>").build()];
+        let long = [block("b").html("<p>One two three four five six</p>").build(), block("c").html("<p>seven eight</p>").build()];
+        let mut segments = vec!(
+            SummarisedContent::new(&summary, short.iter().collect()),
+            SummarisedContent::new(&summary, long.iter().collect()),
+            SummarisedContent { summary: None, events: long.iter().collect(), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() },
+        );
+
+        let filter = LengthFilter { min_input_tokens: Some(2), min_summary_tokens: Some(1), ..LengthFilter::default() };
+        let mut counts = LengthFilterCounts::default();
+        measure_and_filter(&mut segments, &WhitespaceCounter {}, None, &filter, &mut counts);
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].events[0].id, "b");
+        assert_eq!(segments[0].lengths.unwrap().input.tokens, 8);
+        assert_eq!(counts, LengthFilterCounts { input_too_short: 1, summary_too_short: 1, ..LengthFilterCounts::default() });
+        assert_eq!(counts.total(), 2);
+
+        let filter = LengthFilter { max_input_tokens: Some(7), ..LengthFilter::default() };
+        assert_eq!(filter.check(&segments[0].lengths.unwrap()), Some(LengthRejection::InputTooLong));
+        assert!(LengthFilter::default().is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::file_store::write_file_atomically;
+use crate::models::SummarisedContent;
+use crate::lengths::LengthFilterCounts;
+
+/// Name of the manifest written at the top of the output path at the end of a harvest
+pub const MANIFEST_FILE:&str = "MANIFEST.json";
+
+/// One output file, as recorded in the manifest. `path` is relative to the output path and uses `/` as the separator.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Running totals of what a harvest has written
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize)]
+pub struct HarvestCounts {
+    pub liveblogs: usize,
+    pub segments: usize,
+    pub blocks: usize,
+    /// Liveblogs left out because of the overwrite policy
+    pub skipped: usize,
+    /// Segments left out because they were too short or too long
+    #[serde(default)]
+    pub length_filtered: LengthFilterCounts,
+    /// Segments left out by the quality rules; the reasons are in the rejects report
+    #[serde(default)]
+    pub quality_rejected: usize,
+    /// Blocks found to be near-duplicates of earlier ones, whether they were flagged or dropped
+    #[serde(default)]
+    pub near_duplicates: usize,
+}
+
+impl HarvestCounts {
+    /// Adds one liveblog, made up of the given segments
+    pub fn add_liveblog(&mut self, segments:&[SummarisedContent]) {
+        self.liveblogs += 1;
+        self.segments += segments.len();
+        self.blocks += segments.iter().map(|s| s.block_count()).sum::<usize>();
+    }
+}
+
+/// A record of what a harvest was asked for and what it produced, so that a dataset can be traced back to the run
+/// that made it and checked for damage later on with `verify`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub tool: String,
+    pub version: String,
+    pub query_tag: String,
+    /// The commandline the harvest was run with, with the CAPI key removed
+    pub arguments: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub counts: HarvestCounts,
+    pub files: Vec<ManifestFile>,
+    /// Objects uploaded to S3, with their `s3://` URL as the path. `verify` only looks under the output path so it
+    /// cannot check these; they are recorded so that they can be checked against the bucket.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uploaded: Vec<ManifestFile>,
+}
+
+impl Manifest {
+    /// Builds the manifest for a finished harvest, hashing each of the given output files. `uploaded` describes the
+    /// objects that were sent to S3 instead.
+    pub fn new(query_tag:&str, arguments:Vec<String>, started_at:DateTime<Utc>, counts:HarvestCounts, base_path:&Path, output_files:&[PathBuf], mut uploaded:Vec<ManifestFile>) -> Result<Manifest, Box<dyn Error>> {
+        let mut files = output_files.iter()
+            .map(|f| describe_file(base_path, f))
+            .collect::<Result<Vec<ManifestFile>, Box<dyn Error>>>()?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        uploaded.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Manifest {
+            tool: env!("CARGO_PKG_NAME").to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            query_tag: query_tag.to_owned(),
+            arguments,
+            started_at,
+            finished_at: Utc::now(),
+            counts,
+            files,
+            uploaded,
+        })
+    }
+
+    /// Writes the manifest to `MANIFEST.json` under the given output path
+    pub fn write(&self, base_path:&Path) -> Result<(), Box<dyn Error>> {
+        let content = serde_json::to_vec_pretty(self)?;
+        write_file_atomically(&base_path.join(MANIFEST_FILE), &content)
+    }
+
+    pub fn read(base_path:&Path) -> Result<Manifest, Box<dyn Error>> {
+        let file_name = base_path.join(MANIFEST_FILE);
+        let file = File::open(&file_name).map_err(|e| format!("could not open {}: {}", file_name.display(), e))?;
+        serde_json::from_reader(io::BufReader::new(file)).map_err(|e| format!("could not read {}: {}", file_name.display(), e).into())
+    }
+}
+
+/// Options whose values must never be recorded
+const SECRET_ARGUMENTS:[&str; 3] = ["--capi-key", "--s3-secret-key", "--s3-session-token"];
+
+/// Returns a copy of the commandline with the values of secrets like `--capi-key` blanked out, so it can be recorded safely
+pub fn redact_arguments(args:impl Iterator<Item=String>) -> Vec<String> {
+    let mut redact_next = false;
+    args.map(|arg| {
+        let secret_with_value = SECRET_ARGUMENTS.iter().find(|s| arg.starts_with(&format!("{}=", s)));
+        if redact_next {
+            redact_next = false;
+            "REDACTED".to_owned()
+        } else if SECRET_ARGUMENTS.contains(&arg.as_str()) || arg=="-c" {
+            redact_next = true;
+            arg
+        } else if let Some(secret) = secret_with_value {
+            format!("{}=REDACTED", secret)
+        } else if arg.starts_with("-c") && !arg.starts_with("--") {
+            "-cREDACTED".to_owned()
+        } else {
+            arg
+        }
+    }).collect()
+}
+
+/// Returns the size and hex-encoded SHA-256 of the given file
+pub fn hash_file(file_name:&Path) -> Result<(u64, String), Box<dyn Error>> {
+    let mut file = File::open(file_name)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    let hash = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    Ok((size, hash))
+}
+
+/// Turns a file under the output path into its manifest path, relative and with `/` separators
+fn relative_path(base_path:&Path, file_name:&Path) -> String {
+    let relative = file_name.strip_prefix(base_path).unwrap_or(file_name);
+    relative.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn describe_file(base_path:&Path, file_name:&Path) -> Result<ManifestFile, Box<dyn Error>> {
+    let (size, sha256) = hash_file(file_name).map_err(|e| format!("could not checksum {}: {}", file_name.display(), e))?;
+    Ok(ManifestFile { path: relative_path(base_path, file_name), size, sha256 })
+}
+
+/// The outcome of checking an output tree against its manifest
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub modified: Vec<String>,
+    /// Objects that were uploaded to S3, which can't be checked from the output path
+    pub unchecked: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Only true if every file the harvest produced was checked and matched
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty() && self.unchecked.is_empty()
+    }
+}
+
+/// Re-hashes every file listed in the manifest under `base_path` and reports any that are missing or have changed
+pub fn verify(base_path:&Path) -> Result<VerifyReport, Box<dyn Error>> {
+    let manifest = Manifest::read(base_path)?;
+    let mut report = VerifyReport {
+        unchecked: manifest.uploaded.iter().map(|o| o.path.to_owned()).collect(),
+        ..VerifyReport::default()
+    };
+
+    for entry in manifest.files.iter() {
+        report.checked += 1;
+        let file_name = base_path.join(&entry.path);
+        if !file_name.exists() {
+            report.missing.push(entry.path.to_owned());
+            continue;
+        }
+        let (size, sha256) = hash_file(&file_name).map_err(|e| format!("could not checksum {}: {}", file_name.display(), e))?;
+        if size!=entry.size || sha256!=entry.sha256 {
+            report.modified.push(entry.path.to_owned());
+        }
+    }
+    Ok(report)
+}
+
+/// Runs `verify` and prints the result, returning an error if anything is missing or modified
+pub fn run_verify(base_path:&str) -> Result<(), Box<dyn Error>> {
+    let report = verify(Path::new(base_path))?;
+    for path in report.missing.iter() {
+        eprintln!("ERROR missing: {}", path);
+    }
+    for path in report.modified.iter() {
+        eprintln!("ERROR modified: {}", path);
+    }
+    for path in report.unchecked.iter() {
+        eprintln!("WARNING not checked: {}", path);
+    }
+
+    if report.is_ok() {
+        eprintln!("INFO all {} files in {} match the manifest", report.checked, base_path);
+        Ok(())
+    } else if report.missing.is_empty() && report.modified.is_empty() {
+        Err(format!("all {} files in {} match the manifest, but {} objects uploaded to S3 were not checked; compare them against the sizes and hashes in the manifest",
+            report.checked, base_path, report.unchecked.len()).into())
+    } else {
+        Err(format!("{} of {} files missing, {} modified", report.missing.len(), report.checked, report.modified.len()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    pub fn test_redact_arguments() {
+        let args = vec!("xtractor", "--capi-key", "secret", "-q", "tone/minutebyminute", "-csecret", "--capi-key=secret", "-c", "secret", "--chop-strategy", "summary", "--s3-secret-key", "secret", "--s3-session-token=secret");
+        let redacted = redact_arguments(args.into_iter().map(|a| a.to_owned()));
+        assert_eq!(redacted, vec!("xtractor", "--capi-key", "REDACTED", "-q", "tone/minutebyminute", "-cREDACTED", "--capi-key=REDACTED", "-c", "REDACTED", "--chop-strategy", "summary", "--s3-secret-key", "REDACTED", "--s3-session-token=REDACTED"));
+    }
+
+    #[test]
+    pub fn test_hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir.path().join("hello.txt");
+        write(&file_name, "hello world").unwrap();
+
+        let (size, hash) = hash_file(&file_name).unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(hash, "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+    }
+
+    #[test]
+    pub fn test_manifest_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        create_dir_all(dir.path().join("news/live/blog")).unwrap();
+        let files = vec!(dir.path().join("news/live/blog/META.json"), dir.path().join("segments.parquet"), dir.path().join("news/live/blog/MAIN.json"));
+        for f in files.iter() {
+            write(f, "content").unwrap();
+        }
+        let counts = HarvestCounts { liveblogs: 1, segments: 2, blocks: 3, skipped: 0, ..HarvestCounts::default() };
+
+        let manifest = Manifest::new("tone/minutebyminute", vec!("xtractor".to_owned()), Utc::now(), counts, dir.path(), &files, vec!()).unwrap();
+        manifest.write(dir.path()).unwrap();
+
+        let read_back = Manifest::read(dir.path()).unwrap();
+        assert_eq!(read_back.query_tag, "tone/minutebyminute");
+        assert_eq!(read_back.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(read_back.counts.blocks, 3);
+        assert_eq!(read_back.files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>(), vec!("news/live/blog/MAIN.json", "news/live/blog/META.json", "segments.parquet"));
+        assert!(verify(dir.path()).unwrap().is_ok());
+
+        write(dir.path().join("news/live/blog/META.json"), "changed").unwrap();
+        std::fs::remove_file(dir.path().join("segments.parquet")).unwrap();
+        let report = verify(dir.path()).unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.missing, vec!("segments.parquet"));
+        assert_eq!(report.modified, vec!("news/live/blog/META.json"));
+        assert!(run_verify(dir.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    pub fn test_uploaded_objects_are_not_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = vec!(dir.path().join("HARVESTED.jsonl"));
+        write(&files[0], "content").unwrap();
+        let uploaded = vec!(ManifestFile { path: "s3://datasets/liveblogs/segments-00000.jsonl".to_owned(), size: 7, sha256: "abc".to_owned() });
+
+        let manifest = Manifest::new("tone/minutebyminute", vec!("xtractor".to_owned()), Utc::now(), HarvestCounts::default(), dir.path(), &files, uploaded).unwrap();
+        manifest.write(dir.path()).unwrap();
+        assert_eq!(Manifest::read(dir.path()).unwrap().uploaded[0].size, 7);
+
+        //the local files match, but that says nothing about what is in the bucket
+        let report = verify(dir.path()).unwrap();
+        assert!(report.missing.is_empty() && report.modified.is_empty());
+        assert_eq!(report.unchecked, vec!("s3://datasets/liveblogs/segments-00000.jsonl"));
+        assert!(!report.is_ok());
+        assert!(run_verify(dir.path().to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use chrono::{DateTime, FixedOffset, Utc};
+use crate::models::*;
+use crate::text::html_to_markdown;
+
+/// Formats a block timestamp for people to read, e.g. `2022-01-02 03:04 UTC`
+pub fn format_timestamp(t:&DateTime<FixedOffset>) -> String {
+    t.with_timezone(&Utc).format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+/// The heading for an event: its time, its title if it has one, and whether it is a key event
+fn event_heading(block:&CapiBlock) -> String {
+    let mut heading = match block.first_published() {
+        Some(t)=>format_timestamp(&t),
+        None=>"Undated".to_owned(),
+    };
+    if let Some(title) = block.attributes.title.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+        heading.push_str(" — ");
+        heading.push_str(title);
+    }
+    if block.is_key_event() {
+        heading.push_str(" (key event)");
+    }
+    heading
+}
+
+/// Renders a segment as a Markdown document for reviewing: the summary's title and text at the top, then each event
+/// in chronological order under a heading with its time and title. Events without a timestamp go at the end.
+pub fn render_segment(segment:&SummarisedContent) -> String {
+    let mut out = String::new();
+
+    match segment.summary {
+        Some(summary)=>{
+            let title = summary.attributes.title.as_deref().map(str::trim).filter(|t| !t.is_empty()).unwrap_or("Summary");
+            out.push_str(&format!("# {}\n\n", title));
+            if let Some(t) = summary.first_published() {
+                out.push_str(&format!("*{}*\n\n", format_timestamp(&t)));
+            }
+            let text = html_to_markdown(&summary.bodyHtml);
+            if !text.is_empty() {
+                out.push_str(&text);
+                out.push_str("\n\n");
+            }
+        },
+        None=>out.push_str("# No summary\n\n"),
+    }
+
+    for event in segment.events_in_time_order() {
+        out.push_str("---\n\n");
+        out.push_str(&format!("## {}\n\n", event_heading(event)));
+        let text = html_to_markdown(&event.bodyHtml);
+        if !text.is_empty() {
+            out.push_str(&text);
+            out.push_str("\n\n");
+        }
+    }
+
+    let trimmed_length = out.trim_end().len();
+    out.truncate(trimmed_length);
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::block;
+
+    #[test]
+    pub fn test_render_segment() {
+        let summary = block("summary").html("<p>This is <em>block</em> summary</p>").title(Some("What we know")).published(Some("2022-01-02T05:00:00Z")).build();
+        let events = [
+            block("later").published(Some("2022-01-02T04:30:00+01:00")).key_event(true).build(),
+            block("undated").published(None).build(),
+            block("earlier").title(Some("Breaking")).published(Some("2022-01-02T03:04:05Z")).build(),
+        ];
+        let segment = SummarisedContent::new(&summary, events.iter().collect());
+
+        let expected = "# What we know\n\n*2022-01-02 05:00 UTC*\n\nThis is *block* summary\n\n\
+            ---\n\n## 2022-01-02 03:04 UTC — Breaking\n\nThis is block earlier\n\n\
+            ---\n\n## 2022-01-02 03:30 UTC (key event)\n\nThis is block later\n\n\
+            ---\n\n## Undated\n\nThis is block undated\n";
+        assert_eq!(render_segment(&segment), expected);
+    }
+
+    #[test]
+    pub fn test_render_segment_without_summary() {
+        let events = [block("only").published(Some("2022-01-02T03:04:05Z")).build()];
+        let segment = SummarisedContent { summary: None, events: events.iter().collect(), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() };
+        assert_eq!(render_segment(&segment), "# No summary\n\n---\n\n## 2022-01-02 03:04 UTC\n\nThis is block only\n");
+    }
+}
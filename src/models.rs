@@ -2,28 +2,21 @@ use serde::{Deserialize, Serialize};
 use chrono::{DateTime, TimeZone, FixedOffset};
 use std::io;
 use std::str;
+use itertools::Itertools;
+use crate::text::html_to_text;
+use crate::lengths::SegmentLengths;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CapiBlockAttributes {
     pub summary:Option<bool>,
     pub title:Option<String>,
     pub pinned:Option<bool>,
+    pub keyEvent:Option<bool>,
 }
 
-impl CapiBlockAttributes {
-    pub fn clone(&self) -> CapiBlockAttributes {
-        CapiBlockAttributes { 
-            summary: self.summary, 
-            title: match &self.title {
-                Some(t)=>Some(t.to_owned()),
-                None=>None
-            },
-            pinned: self.pinned
-         }
-    }
-}
-
-#[derive(Debug, Deserialize, Serialize)]
+/// A block as it comes from CAPI. When written out it also gets a `bodyText` field, the plain text of `bodyHtml`,
+/// so that consumers do not each have to strip the HTML themselves.
+#[derive(Debug, Clone, Deserialize)]
 pub struct CapiBlock {
     pub id:String,
     pub bodyHtml:String,
@@ -31,20 +24,88 @@ pub struct CapiBlock {
     pub firstPublishedDate:Option<String>,
 }
 
+/// What a `CapiBlock` is serialized as
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CapiBlockOutput<'a> {
+    id:&'a str,
+    body_html:&'a str,
+    body_text:String,
+    attributes:&'a CapiBlockAttributes,
+    first_published_date:&'a Option<String>,
+}
+
+impl Serialize for CapiBlock {
+    fn serialize<S:serde::Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        CapiBlockOutput {
+            id: &self.id,
+            body_html: &self.bodyHtml,
+            body_text: self.body_text(),
+            attributes: &self.attributes,
+            first_published_date: &self.firstPublishedDate,
+        }.serialize(serializer)
+    }
+}
+
 impl CapiBlock {
-    pub fn clone(&self) -> CapiBlock {
-        CapiBlock { 
-            id: self.id.to_owned(), 
-            bodyHtml: self.bodyHtml.to_owned(), 
-            attributes: self.attributes.clone(),
-            firstPublishedDate: self.firstPublishedDate.to_owned(),
-         }
+    /// The block's HTML converted to plain text
+    pub fn body_text(&self) -> String {
+        html_to_text(&self.bodyHtml)
     }
+
+    /// Parses `firstPublishedDate`, returning None if it is missing or not a valid RFC3339 timestamp
+    pub fn first_published(&self) -> Option<DateTime<FixedOffset>> {
+        self.firstPublishedDate.as_ref().and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+    }
+
+    pub fn is_key_event(&self) -> bool {
+        self.attributes.keyEvent.unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CapiAsset {
+    pub r#type:String,
+    pub mimeType:Option<String>,
+    pub file:Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CapiImageTypeData {
+    pub caption:Option<String>,
+    pub credit:Option<String>,
+    pub alt:Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CapiTextTypeData {
+    pub html:Option<String>,
+}
+
+/// One of the structured elements (text, image, embed etc.) that make up a block.
+/// Only the type data for text and images is modelled, anything else is ignored.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CapiElement {
+    pub r#type:String,
+    #[serde(default)]
+    pub assets:Vec<CapiAsset>,
+    pub imageTypeData:Option<CapiImageTypeData>,
+    pub textTypeData:Option<CapiTextTypeData>,
+}
+
+/// The main block of a liveblog, i.e. the opening media and lede.
+/// Unlike the body blocks we keep its elements, since the media is usually only available there.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CapiMainBlock {
+    #[serde(flatten)]
+    pub block:CapiBlock,
+    #[serde(default)]
+    pub elements:Vec<CapiElement>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CapiBlocksContainer {
-    pub main:CapiBlock,
+    pub main:CapiMainBlock,
     pub body:Vec<CapiBlock>,
 }
 
@@ -61,14 +122,55 @@ impl Clone for CapiTag {
     }
 }
 
-impl CapiBlocksContainer {
-    pub fn count_body_blocks(&self) -> usize {
+/// Counts of the blocks in a liveblog's body, either as CAPI sent it or as `prepare_body` leaves it for chopping
+pub trait BlockCounts {
+    fn count_body_blocks(&self) -> usize;
+    fn count_summary_blocks(&self) -> usize;
+    fn count_key_event_blocks(&self) -> usize;
+}
+
+impl BlockCounts for CapiBlocksContainer {
+    fn count_body_blocks(&self) -> usize {
         return self.body.len();
     }
 
-    pub fn count_summary_blocks(&self) -> usize {
+    fn count_summary_blocks(&self) -> usize {
         self.body.iter().filter(|b| b.attributes.summary.unwrap_or(false)).count()
     }
+
+    fn count_key_event_blocks(&self) -> usize {
+        self.body.iter().filter(|b| b.is_key_event()).count()
+    }
+}
+
+impl BlockCounts for [&CapiBlock] {
+    fn count_body_blocks(&self) -> usize {
+        self.len()
+    }
+
+    fn count_summary_blocks(&self) -> usize {
+        self.iter().filter(|b| b.attributes.summary.unwrap_or(false)).count()
+    }
+
+    fn count_key_event_blocks(&self) -> usize {
+        self.iter().filter(|b| b.is_key_event()).count()
+    }
+}
+
+impl CapiBlocksContainer {
+    /// Returns the ids of all pinned blocks in the body, without duplicates, in the order they first appear
+    pub fn pinned_block_ids(&self) -> Vec<String> {
+        self.body.iter()
+            .filter(|b| b.attributes.pinned.unwrap_or(false))
+            .map(|b| b.id.to_owned())
+            .unique()
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CapiDocumentFields {
+    pub standfirst:Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -77,7 +179,14 @@ pub struct CapiDocument {
     pub r#type: String,
     pub webPublicationDate: DateTime<FixedOffset>,
     pub blocks: CapiBlocksContainer,
-    pub tags: Vec<CapiTag>
+    pub tags: Vec<CapiTag>,
+    pub fields: Option<CapiDocumentFields>,
+}
+
+impl CapiDocument {
+    pub fn standfirst(&self) -> Option<&str> {
+        self.fields.as_ref().and_then(|f| f.standfirst.as_deref())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -98,22 +207,130 @@ pub struct CapiResponseEnvelope {
     pub response:CapiResponse,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct SummarisedContent {
-    pub summary: Option<CapiBlock>,
-    pub events: Vec<CapiBlock>,
+/// The opening of a liveblog: its main block and the standfirst of the document
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MainContent<'a> {
+    pub main: &'a CapiMainBlock,
+    pub standfirst: Option<&'a str>,
+}
+
+impl<'a> MainContent<'a> {
+    pub fn from_document(doc:&'a CapiDocument) -> MainContent<'a> {
+        MainContent { main: &doc.blocks.main, standfirst: doc.standfirst() }
+    }
+}
+
+/// A segment of a liveblog: a summary block plus the event blocks that it summarises.
+/// The blocks are borrowed from the `CapiBlocksContainer` that was chopped, so no block content is copied.
+/// `key_events` holds the ids of any blocks in the segment (summary included) that are marked as key events.
+/// `context` is only set if the liveblog's main content was requested on every segment, and `lengths` once the
+/// segment has been measured. `duplicate_blocks` holds the ids of any blocks flagged as near-duplicates of blocks
+/// seen earlier in the harvest.
+#[derive(Debug)]
+pub struct SummarisedContent<'a> {
+    pub summary: Option<&'a CapiBlock>,
+    pub events: Vec<&'a CapiBlock>,
+    pub key_events: Vec<&'a str>,
+    pub context: Option<MainContent<'a>>,
+    pub lengths: Option<SegmentLengths>,
+    pub duplicate_blocks: Vec<&'a str>,
+}
+
+/// How a segment is laid out when serialized, with each of its blocks written as a `B`. Both the default
+/// serialization and the projected one go through this, so they always have the same fields.
+#[derive(Serialize)]
+pub struct SegmentLayout<'s, B:Serialize> {
+    summary: Option<B>,
+    events: Vec<B>,
+    key_events: &'s [&'s str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<&'s MainContent<'s>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lengths: Option<&'s SegmentLengths>,
+    #[serde(skip_serializing_if = "no_ids")]
+    duplicate_blocks: &'s [&'s str],
 }
 
-impl SummarisedContent {
-    pub fn empty() -> SummarisedContent {
-        SummarisedContent { summary: None, events: vec!() }
+fn no_ids(ids:&&[&str]) -> bool {
+    ids.is_empty()
+}
+
+impl Serialize for SummarisedContent<'_> {
+    fn serialize<S:serde::Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        self.layout(|block| block).serialize(serializer)
+    }
+}
+
+impl<'a> SummarisedContent<'a> {
+    /// The serialized layout of the segment, with `block` deciding how each block is written
+    pub fn layout<'s, B:Serialize>(&'s self, block:impl Fn(&'a CapiBlock) -> B) -> SegmentLayout<'s, B> {
+        SegmentLayout {
+            summary: self.summary.map(&block),
+            events: self.events.iter().map(|e| block(e)).collect(),
+            key_events: &self.key_events,
+            context: self.context.as_ref(),
+            lengths: self.lengths.as_ref(),
+            duplicate_blocks: &self.duplicate_blocks,
+        }
+    }
+
+    pub fn empty() -> SummarisedContent<'a> {
+        SummarisedContent { summary: None, events: vec!(), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() }
+    }
+
+    pub fn new(summary:&'a CapiBlock, events: Vec<&'a CapiBlock>) -> SummarisedContent<'a> {
+        let mut content = SummarisedContent::empty();
+        if summary.is_key_event() {
+            content.key_events.push(&summary.id);
+        }
+        content.summary = Some(summary);
+        for e in events {
+            content.push_event(e);
+        }
+        content
     }
 
-    pub fn new(summary:CapiBlock, events: Vec<CapiBlock>) -> SummarisedContent {
-        SummarisedContent {
-            summary: Some(summary),
-            events: events,
+    /// Adds a block to the end of the events, keeping `key_events` up to date
+    pub fn push_event(&mut self, block:&'a CapiBlock) {
+        if block.is_key_event() {
+            self.key_events.push(&block.id);
         }
+        self.events.push(block);
+    }
+
+    /// Keeps only the events that `keep` returns true for, keeping `key_events` up to date
+    pub fn retain_events(&mut self, mut keep:impl FnMut(&CapiBlock) -> bool) {
+        self.events.retain(|e| keep(e));
+        let summary_id = self.summary.map(|s| s.id.as_str());
+        let events = &self.events;
+        self.key_events.retain(|id| Some(*id)==summary_id || events.iter().any(|e| e.id==*id));
+    }
+
+    /// All of the blocks in the segment, summary first
+    pub fn blocks(&self) -> impl Iterator<Item=&'a CapiBlock> + '_ {
+        self.summary.into_iter().chain(self.events.iter().copied())
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.events.len() + if self.summary.is_some() { 1 } else { 0 }
+    }
+
+    /// The events from earliest to latest. Events without a timestamp go at the end, and the sort is stable so events
+    /// published at the same moment keep their order.
+    pub fn events_in_time_order(&self) -> Vec<&'a CapiBlock> {
+        let mut events = self.events.clone();
+        events.sort_by_key(|e| (e.first_published().is_none(), e.first_published()));
+        events
+    }
+
+    /// Returns the earliest and latest publication times of the blocks in the segment, or None if none of them has one
+    pub fn time_range(&self) -> Option<(DateTime<FixedOffset>, DateTime<FixedOffset>)> {
+        self.blocks()
+            .filter_map(|b| b.first_published())
+            .fold(None, |range, t| match range {
+                None=>Some((t, t)),
+                Some((first, last))=>Some((first.min(t), last.max(t))),
+            })
     }
 }
 
@@ -124,7 +341,9 @@ pub struct Stats<'a> {
     pub retrieved_at: DateTime<FixedOffset>,
     pub summary_block_count: usize,
     pub total_block_count: usize,
+    pub key_event_block_count: usize,
     pub keyword_tags: Vec<CapiTag>,
+    pub pinned_block_ids: Vec<String>,
 }
 
 impl Stats<'_> {
@@ -153,11 +372,14 @@ mod tests {
     #[test]
     pub fn test_count_empty_capi_blocks() {
         let to_test: CapiBlocksContainer = CapiBlocksContainer {
-            main: CapiBlock {
-                id: "fred".to_owned(),
-                bodyHtml: "<b>Test</b".to_owned(),
-                attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
-                firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fred".to_owned(),
+                    bodyHtml: "<b>Test</b".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+                },
+                elements: vec!(),
             },
             body: vec!(),
         };
@@ -168,29 +390,32 @@ mod tests {
     #[test]
     pub fn test_count_multiple_capi_blocks() {
         let to_test: CapiBlocksContainer = CapiBlocksContainer {
-            main: CapiBlock {
-                id: "fred".to_owned(),
-                bodyHtml: "<b>Test</b".to_owned(),
-                attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
-                firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fred".to_owned(),
+                    bodyHtml: "<b>Test</b".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+                },
+                elements: vec!(),
             },
             body: vec!(
                 CapiBlock {
                     id: "fred".to_owned(),
                     bodyHtml: "<b>Test</b".to_owned(),
-                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
                     firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
                 },
                 CapiBlock {
                     id: "kate".to_owned(),
                     bodyHtml: "<b>Test</b".to_owned(),
-                    attributes: CapiBlockAttributes { summary: Some(true), title: Some("this is a summary".to_owned()), pinned: Some(false) },
+                    attributes: CapiBlockAttributes { summary: Some(true), title: Some("this is a summary".to_owned()), pinned: Some(false), keyEvent: None },
                     firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
                 },
                 CapiBlock {
                     id: "bob".to_owned(),
                     bodyHtml: "<b>Test</b".to_owned(),
-                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
                     firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
                 },
             ),
@@ -202,29 +427,32 @@ mod tests {
     #[test]
     pub fn test_count_summary_blocks() {
         let to_test: CapiBlocksContainer = CapiBlocksContainer {
-            main: CapiBlock {
-                id: "fred".to_owned(),
-                bodyHtml: "<b>Test</b".to_owned(),
-                attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
-                firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fred".to_owned(),
+                    bodyHtml: "<b>Test</b".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+                },
+                elements: vec!(),
             },
             body: vec!(
                 CapiBlock {
                     id: "fred".to_owned(),
                     bodyHtml: "<b>Test</b".to_owned(),
-                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
                     firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
                 },
                 CapiBlock {
                     id: "kate".to_owned(),
                     bodyHtml: "<b>Test</b".to_owned(),
-                    attributes: CapiBlockAttributes { summary: Some(true), title: Some("this is a summary".to_owned()), pinned: Some(false) },
+                    attributes: CapiBlockAttributes { summary: Some(true), title: Some("this is a summary".to_owned()), pinned: Some(false), keyEvent: None },
                     firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
                 },
                 CapiBlock {
                     id: "bob".to_owned(),
                     bodyHtml: "<b>Test</b".to_owned(),
-                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false) },
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
                     firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
                 },
             ),
@@ -233,6 +461,97 @@ mod tests {
         assert_eq!(to_test.count_summary_blocks(), 1);
     }
 
+    #[test]
+    pub fn test_pinned_block_ids() {
+        let to_test: CapiBlocksContainer = CapiBlocksContainer {
+            main: CapiMainBlock {
+                block: CapiBlock {
+                    id: "fred".to_owned(),
+                    bodyHtml: "<b>Test</b".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+                },
+                elements: vec!(),
+            },
+            body: vec!(
+                CapiBlock {
+                    id: "kate".to_owned(),
+                    bodyHtml: "<b>Test</b".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(true), keyEvent: None },
+                    firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+                },
+                CapiBlock {
+                    id: "fred".to_owned(),
+                    bodyHtml: "<b>Test</b".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+                    firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+                },
+                CapiBlock {
+                    id: "kate".to_owned(),
+                    bodyHtml: "<b>Test</b".to_owned(),
+                    attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(true), keyEvent: None },
+                    firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned())
+                },
+            ),
+        };
+
+        assert_eq!(to_test.pinned_block_ids(), vec!("kate".to_owned()));
+    }
+
+    #[test]
+    pub fn test_main_content_as_context() {
+        let main = CapiMainBlock {
+            block: CapiBlock {
+                id: "main".to_owned(),
+                bodyHtml: "<figure></figure>".to_owned(),
+                attributes: CapiBlockAttributes { summary: None, title: None, pinned: None, keyEvent: None },
+                firstPublishedDate: None,
+            },
+            elements: vec!(CapiElement {
+                r#type: "image".to_owned(),
+                assets: vec!(),
+                imageTypeData: Some(CapiImageTypeData { caption: Some("A picture".to_owned()), credit: None, alt: None }),
+                textTypeData: None,
+            }),
+        };
+        let main_content = MainContent { main: &main, standfirst: Some("<p>What happened today</p>") };
+
+        let mut segment = SummarisedContent::empty();
+        let without_context = serde_json::to_value(&segment).unwrap();
+        assert!(without_context.get("context").is_none());
+
+        segment.context = Some(main_content);
+        let with_context = serde_json::to_value(&segment).unwrap();
+        assert_eq!(with_context["context"]["standfirst"], "<p>What happened today</p>");
+        assert_eq!(with_context["context"]["main"]["id"], "main");
+        assert_eq!(with_context["context"]["main"]["elements"][0]["imageTypeData"]["caption"], "A picture");
+    }
+
+    #[test]
+    pub fn test_segment_time_range() {
+        let make_block = |id:&str, date:Option<&str>| CapiBlock {
+            id: id.to_owned(),
+            bodyHtml: "".to_owned(),
+            attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+            firstPublishedDate: date.map(|d| d.to_owned()),
+        };
+        let summary = make_block("s", Some("2022-01-02T05:00:00Z"));
+        let early = make_block("a", Some("2022-01-02T03:00:00Z"));
+        let undated = make_block("b", None);
+        let late = make_block("c", Some("2022-01-02T06:00:00Z"));
+
+        let segment = SummarisedContent::new(&summary, vec!(&early, &undated, &late));
+        assert_eq!(segment.block_count(), 4);
+        let (first, last) = segment.time_range().unwrap();
+        assert_eq!(first, DateTime::parse_from_rfc3339("2022-01-02T03:00:00Z").unwrap());
+        assert_eq!(last, DateTime::parse_from_rfc3339("2022-01-02T06:00:00Z").unwrap());
+
+        let mut undated_segment = SummarisedContent::empty();
+        undated_segment.push_event(&undated);
+        assert_eq!(undated_segment.block_count(), 1);
+        assert!(undated_segment.time_range().is_none());
+    }
+
     #[test]
     pub fn test_write_stats_json() {
         let to_test = Stats {
@@ -241,11 +560,29 @@ mod tests {
             retrieved_at: DateTime::parse_from_rfc3339("2022-01-02T03:04:05.678Z").unwrap(),
             summary_block_count: 1,
             total_block_count: 5,
+            key_event_block_count: 2,
             keyword_tags: vec!(),
+            pinned_block_ids: vec!("pinned-block".to_owned()),
         };
 
-        let expected = "{\"original_id\":\"original-id-here\",\"web_publication_date\":\"2022-01-02T03:04:05.678Z\",\"retrieved_at\":\"2022-01-02T03:04:05.678Z\",\"summary_block_count\":1,\"total_block_count\":5,\"keyword_tags\":[]}";
+        let expected = "{\"original_id\":\"original-id-here\",\"web_publication_date\":\"2022-01-02T03:04:05.678Z\",\"retrieved_at\":\"2022-01-02T03:04:05.678Z\",\"summary_block_count\":1,\"total_block_count\":5,\"key_event_block_count\":2,\"keyword_tags\":[],\"pinned_block_ids\":[\"pinned-block\"]}";
         let marshalled = to_test.write_json_string().unwrap();
         assert_eq!(marshalled, expected);
     }
+
+    #[test]
+    pub fn test_block_json_has_body_text() {
+        let block = CapiBlock {
+            id: "block".to_owned(),
+            bodyHtml: "<p>Fish &amp; chips</p><ul><li>one</li></ul>".to_owned(),
+            attributes: CapiBlockAttributes { summary: None, title: None, pinned: None, keyEvent: None },
+            firstPublishedDate: None,
+        };
+        let expected = "{\"id\":\"block\",\"bodyHtml\":\"<p>Fish &amp; chips</p><ul><li>one</li></ul>\",\"bodyText\":\"Fish & chips\\n\\n- one\",\"attributes\":{\"summary\":null,\"title\":null,\"pinned\":null,\"keyEvent\":null},\"firstPublishedDate\":null}";
+        assert_eq!(serde_json::to_string(&block).unwrap(), expected);
+
+        //bodyText is worked out from the HTML, so a block read back in is the same as the original
+        let read_back:CapiBlock = serde_json::from_str(expected).unwrap();
+        assert_eq!(read_back.bodyHtml, block.bodyHtml);
+    }
 }
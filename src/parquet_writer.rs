@@ -0,0 +1,374 @@
+use std::error::Error;
+use std::fs::{create_dir_all, rename, File};
+use std::path::PathBuf;
+use std::sync::Arc;
+use arrow_array::builder::{ListBuilder, StringBuilder, StructBuilder, TimestampMillisecondBuilder, UInt32Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, FixedOffset};
+use clap::ValueEnum;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, GzipLevel, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use crate::models::*;
+use crate::writer::OutputSink;
+use crate::file_store::find_last_numbered_file;
+
+/// The compression codecs that can be selected from the commandline for parquet output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ParquetCompression {
+    None,
+    Snappy,
+    Gzip,
+    Zstd,
+}
+
+impl ParquetCompression {
+    fn codec(&self) -> Compression {
+        match self {
+            ParquetCompression::None=>Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy=>Compression::SNAPPY,
+            ParquetCompression::Gzip=>Compression::GZIP(GzipLevel::default()),
+            ParquetCompression::Zstd=>Compression::ZSTD(ZstdLevel::default()),
+        }
+    }
+}
+
+fn tag_fields() -> Fields {
+    Fields::from(vec!(
+        Field::new("id", DataType::Utf8, false),
+        Field::new("web_title", DataType::Utf8, false),
+        Field::new("type", DataType::Utf8, false),
+    ))
+}
+
+/// The schema of the segments file. Columns are only ever added to the end of this, so that existing readers keep working.
+pub fn segment_schema() -> SchemaRef {
+    let timestamp = DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()));
+    let string_list = DataType::List(Arc::new(Field::new("item", DataType::Utf8, true)));
+
+    Arc::new(Schema::new(vec!(
+        Field::new("liveblog_id", DataType::Utf8, false),
+        Field::new("web_publication_date", timestamp.clone(), false),
+        Field::new("retrieved_at", timestamp, false),
+        Field::new("segment_index", DataType::UInt32, false),
+        Field::new("summary_block_id", DataType::Utf8, true),
+        Field::new("summary_title", DataType::Utf8, true),
+        Field::new("summary_html", DataType::Utf8, true),
+        Field::new("summary_text", DataType::Utf8, true),
+        Field::new("event_block_ids", string_list.clone(), false),
+        Field::new("event_texts", string_list.clone(), false),
+        Field::new("key_event_ids", string_list, false),
+        Field::new("tags", DataType::List(Arc::new(Field::new("item", DataType::Struct(tag_fields()), true))), false),
+        Field::new("summary_block_count", DataType::UInt32, false),
+        Field::new("total_block_count", DataType::UInt32, false),
+        Field::new("input_tokens", DataType::UInt32, true),
+        Field::new("summary_tokens", DataType::UInt32, true),
+    )))
+}
+
+/// One row of the segments file, held until the liveblog is finished
+struct SegmentRow {
+    segment_index: u32,
+    summary_block_id: Option<String>,
+    summary_title: Option<String>,
+    summary_html: Option<String>,
+    summary_text: Option<String>,
+    event_block_ids: Vec<String>,
+    event_texts: Vec<String>,
+    key_event_ids: Vec<String>,
+    input_tokens: Option<u32>,
+    summary_tokens: Option<u32>,
+}
+
+/// The details of the liveblog being written that are copied into each row
+struct LiveblogDetails {
+    liveblog_id: String,
+    web_publication_date: DateTime<FixedOffset>,
+    retrieved_at: DateTime<FixedOffset>,
+    keyword_tags: Vec<CapiTag>,
+    summary_block_count: u32,
+    total_block_count: u32,
+}
+
+/// Writes every segment as a row of `segments-00000.parquet` in the output path, using the schema from `segment_schema`.
+/// Each run writes a new file, numbered after any that are already there, so an earlier harvest is never replaced.
+/// Each liveblog is handed to the parquet writer as one record batch once it is finished; the writer takes care of
+/// cutting the batches up into row groups.
+/// The file is written under a temporary name and only renamed into place by `flush`, once its footer is written, so
+/// a run that fails part way never leaves a truncated `segments-NNNNN.parquet` behind.
+pub struct ParquetSink {
+    writer: Option<ArrowWriter<File>>,
+    file_name: PathBuf,
+    temp_name: PathBuf,
+    liveblog: Option<LiveblogDetails>,
+    pending_rows: Vec<SegmentRow>,
+}
+
+impl ParquetSink {
+    pub fn new(base_path:&str, compression:ParquetCompression, row_group_size:usize) -> Result<ParquetSink, Box<dyn Error>> {
+        let base_path = PathBuf::from(base_path);
+        create_dir_all(&base_path)?;
+        let file_index = find_last_numbered_file(&base_path, "segments-", ".parquet")?.map_or(0, |i| i + 1);
+        let file_name = base_path.join(format!("segments-{:05}.parquet", file_index));
+        let temp_name = base_path.join(format!(".segments-{:05}.parquet.{}.tmp", file_index, std::process::id()));
+        let file = File::create(&temp_name)?;
+
+        let props = WriterProperties::builder()
+            .set_compression(compression.codec())
+            .set_max_row_group_size(row_group_size.max(1))
+            .build();
+        let writer = ArrowWriter::try_new(file, segment_schema(), Some(props))?;
+        eprintln!("DEBUG writing segments to {}", file_name.display());
+
+        Ok(ParquetSink {
+            writer: Some(writer),
+            file_name,
+            temp_name,
+            liveblog: None,
+            pending_rows: vec!(),
+        })
+    }
+
+    /// Builds a record batch out of the given liveblog's segments and hands it to the parquet writer
+    fn write_batch(&mut self, details:LiveblogDetails, rows:Vec<SegmentRow>) -> Result<(), Box<dyn Error>> {
+        let mut liveblog_id = StringBuilder::new();
+        let mut web_publication_date = TimestampMillisecondBuilder::new().with_timezone("UTC");
+        let mut retrieved_at = TimestampMillisecondBuilder::new().with_timezone("UTC");
+        let mut segment_index = UInt32Builder::new();
+        let mut summary_block_id = StringBuilder::new();
+        let mut summary_title = StringBuilder::new();
+        let mut summary_html = StringBuilder::new();
+        let mut summary_text = StringBuilder::new();
+        let mut event_block_ids = ListBuilder::new(StringBuilder::new());
+        let mut event_texts = ListBuilder::new(StringBuilder::new());
+        let mut key_event_ids = ListBuilder::new(StringBuilder::new());
+        let mut tags = ListBuilder::new(StructBuilder::from_fields(tag_fields(), 0));
+        let mut summary_block_count = UInt32Builder::new();
+        let mut total_block_count = UInt32Builder::new();
+        let mut input_tokens = UInt32Builder::new();
+        let mut summary_tokens = UInt32Builder::new();
+
+        for row in rows {
+            liveblog_id.append_value(&details.liveblog_id);
+            web_publication_date.append_value(details.web_publication_date.timestamp_millis());
+            retrieved_at.append_value(details.retrieved_at.timestamp_millis());
+            segment_index.append_value(row.segment_index);
+            summary_block_id.append_option(row.summary_block_id);
+            summary_title.append_option(row.summary_title);
+            summary_html.append_option(row.summary_html);
+            summary_text.append_option(row.summary_text);
+            for v in row.event_block_ids {
+                event_block_ids.values().append_value(v);
+            }
+            event_block_ids.append(true);
+            for v in row.event_texts {
+                event_texts.values().append_value(v);
+            }
+            event_texts.append(true);
+            for v in row.key_event_ids {
+                key_event_ids.values().append_value(v);
+            }
+            key_event_ids.append(true);
+            for tag in details.keyword_tags.iter() {
+                let tag_builder = tags.values();
+                for (i, v) in [&tag.id, &tag.webTitle, &tag.r#type].iter().enumerate() {
+                    tag_builder.field_builder::<StringBuilder>(i).ok_or("tag field builder missing")?.append_value(v);
+                }
+                tag_builder.append(true);
+            }
+            tags.append(true);
+            summary_block_count.append_value(details.summary_block_count);
+            total_block_count.append_value(details.total_block_count);
+            input_tokens.append_option(row.input_tokens);
+            summary_tokens.append_option(row.summary_tokens);
+        }
+
+        let columns:Vec<ArrayRef> = vec!(
+            Arc::new(liveblog_id.finish()),
+            Arc::new(web_publication_date.finish()),
+            Arc::new(retrieved_at.finish()),
+            Arc::new(segment_index.finish()),
+            Arc::new(summary_block_id.finish()),
+            Arc::new(summary_title.finish()),
+            Arc::new(summary_html.finish()),
+            Arc::new(summary_text.finish()),
+            Arc::new(event_block_ids.finish()),
+            Arc::new(event_texts.finish()),
+            Arc::new(key_event_ids.finish()),
+            Arc::new(tags.finish()),
+            Arc::new(summary_block_count.finish()),
+            Arc::new(total_block_count.finish()),
+            Arc::new(input_tokens.finish()),
+            Arc::new(summary_tokens.finish()),
+        );
+        let batch = RecordBatch::try_new(segment_schema(), columns)?;
+
+        match self.writer.as_mut() {
+            Some(w)=>Ok(w.write(&batch)?),
+            None=>Err("parquet sink has already been closed".into())
+        }
+    }
+}
+
+impl OutputSink for ParquetSink {
+    fn begin_liveblog(&mut self, _main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        self.liveblog = Some(LiveblogDetails {
+            liveblog_id: stats.original_id.to_owned(),
+            web_publication_date: stats.web_publication_date,
+            retrieved_at: stats.retrieved_at,
+            keyword_tags: stats.keyword_tags.clone(),
+            summary_block_count: 0,
+            total_block_count: 0,
+        });
+        Ok(())
+    }
+
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+        self.pending_rows.push(SegmentRow {
+            segment_index: u32::try_from(index)?,
+            summary_block_id: segment.summary.map(|s| s.id.to_owned()),
+            summary_title: segment.summary.and_then(|s| s.attributes.title.to_owned()),
+            summary_html: segment.summary.map(|s| s.bodyHtml.to_owned()),
+            summary_text: segment.summary.map(|s| s.body_text()),
+            event_block_ids: segment.events.iter().map(|e| e.id.to_owned()).collect(),
+            event_texts: segment.events.iter().map(|e| e.body_text()).collect(),
+            key_event_ids: segment.key_events.iter().map(|id| id.to_string()).collect(),
+            input_tokens: segment.lengths.map(|l| u32::try_from(l.input.tokens)).transpose()?,
+            summary_tokens: segment.lengths.map(|l| u32::try_from(l.summary.tokens)).transpose()?,
+        });
+        Ok(())
+    }
+
+    fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        if let Some(details) = self.liveblog.as_mut() {
+            details.summary_block_count = u32::try_from(stats.summary_block_count)?;
+            details.total_block_count = u32::try_from(stats.total_block_count)?;
+        }
+        Ok(())
+    }
+
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        let details = self.liveblog.take().ok_or("finish_liveblog called before begin_liveblog")?;
+        let rows = std::mem::take(&mut self.pending_rows);
+        if rows.is_empty() {
+            return Ok(());
+        }
+        self.write_batch(details, rows)
+    }
+
+    /// Drops the liveblog's buffered rows, so they are not written out under the next liveblog
+    fn abort_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.liveblog = None;
+        self.pending_rows.clear();
+        Ok(())
+    }
+
+    /// Writes any remaining rows and the parquet footer. Nothing more can be written after this.
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(w) = self.writer.take() {
+            w.close()?;
+            rename(&self.temp_name, &self.file_name)?;
+        }
+        Ok(())
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        vec!(self.file_name.clone())
+    }
+
+    /// Nothing is under its real name until the footer is written in `flush`
+    fn writes_through(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{block, keyword_tag, stats};
+    use crate::writer::write_liveblog;
+    use crate::lengths::{SegmentLengths, TextLengths};
+    use arrow_array::{Array, ListArray, StringArray, StructArray, UInt32Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    pub fn test_write_parquet() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = block("main").main();
+        let blocks = [block("a").build(), block("b").summary(true).build(), block("c").build()];
+        let segments = vec!(
+            SummarisedContent { summary: None, events: vec!(&blocks[0]), key_events: vec!(), context: None, lengths: Some(SegmentLengths { input: TextLengths { words: 4, characters: 15, tokens: 4 }, summary: TextLengths::default() }), duplicate_blocks: vec!() },
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2])),
+        );
+
+        //six segments with a row group size of 4 should give two row groups
+        let mut sink = ParquetSink::new(dir.path().to_str().unwrap(), ParquetCompression::Zstd, 4).unwrap();
+        for id in ["news/live/1", "news/live/2", "news/live/3"] {
+            write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats(id).tags(vec!(keyword_tag("world/world"))).build()).unwrap();
+        }
+        //the file only appears under its real name once the footer has been written
+        assert!(!sink.writes_through());
+        assert!(!dir.path().join("segments-00000.parquet").exists());
+        sink.flush().unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let file = File::open(dir.path().join("segments-00000.parquet")).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+        assert_eq!(builder.metadata().num_row_groups(), 2);
+        assert_eq!(builder.schema(), &segment_schema());
+
+        let batches:Vec<RecordBatch> = builder.build().unwrap().map(|b| b.unwrap()).collect();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 6);
+        let first = &batches[0];
+
+        let ids = first.column_by_name("liveblog_id").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(ids.value(0), "news/live/1");
+        let indexes = first.column_by_name("segment_index").unwrap().as_any().downcast_ref::<UInt32Array>().unwrap();
+        assert_eq!(indexes.value(1), 1);
+        let summary_text = first.column_by_name("summary_text").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+        assert!(summary_text.is_null(0));
+        assert_eq!(summary_text.value(1), "This is block b");
+
+        let event_texts = first.column_by_name("event_texts").unwrap().as_any().downcast_ref::<ListArray>().unwrap();
+        let texts = event_texts.value(1);
+        assert_eq!(texts.as_any().downcast_ref::<StringArray>().unwrap().value(0), "This is block c");
+
+        let tags = first.column_by_name("tags").unwrap().as_any().downcast_ref::<ListArray>().unwrap();
+        let tag_values = tags.value(0);
+        let tag_struct = tag_values.as_any().downcast_ref::<StructArray>().unwrap();
+        let tag_ids = tag_struct.column_by_name("id").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(tag_ids.value(0), "world/world");
+
+        let input_tokens = first.column_by_name("input_tokens").unwrap().as_any().downcast_ref::<UInt32Array>().unwrap();
+        assert_eq!(input_tokens.value(0), 4);
+        assert!(input_tokens.is_null(1));
+
+        let total = first.column_by_name("total_block_count").unwrap().as_any().downcast_ref::<UInt32Array>().unwrap();
+        assert_eq!(total.value(0), 3);
+    }
+
+    #[test]
+    pub fn test_aborted_liveblog_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = block("main").main();
+        let blocks = [block("a").summary(true).build(), block("b").build()];
+        let main_content = MainContent { main: &main, standfirst: None };
+        let mut sink = ParquetSink::new(dir.path().to_str().unwrap(), ParquetCompression::Snappy, 100).unwrap();
+
+        let failed = stats("news/live/failed").build();
+        sink.begin_liveblog(&main_content, &failed).unwrap();
+        sink.write_segment(0, &SummarisedContent::new(&blocks[0], vec!(&blocks[1]))).unwrap();
+        sink.abort_liveblog().unwrap();
+
+        write_liveblog(&mut sink, &[SummarisedContent::new(&blocks[0], vec!())], &main_content, &stats("news/live/next").build()).unwrap();
+        sink.flush().unwrap();
+
+        let file = File::open(dir.path().join("segments-00000.parquet")).unwrap();
+        let batches:Vec<RecordBatch> = ParquetRecordBatchReaderBuilder::try_new(file).unwrap().build().unwrap().map(|b| b.unwrap()).collect();
+        let ids:Vec<&str> = batches.iter()
+            .flat_map(|b| b.column_by_name("liveblog_id").unwrap().as_any().downcast_ref::<StringArray>().unwrap().iter().map(|id| id.unwrap()))
+            .collect();
+        assert_eq!(ids, vec!("news/live/next"));
+    }
+}
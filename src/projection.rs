@@ -0,0 +1,206 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use crate::models::*;
+
+/// The fields of a block that can be picked for output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum BlockField {
+    Id,
+    BodyHtml,
+    BodyText,
+    Attributes,
+    FirstPublishedDate,
+}
+
+/// The fields of the stats that can be picked for output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum StatsField {
+    OriginalId,
+    WebPublicationDate,
+    RetrievedAt,
+    SummaryBlockCount,
+    TotalBlockCount,
+    KeyEventBlockCount,
+    KeywordTags,
+    PinnedBlockIds,
+}
+
+/// Which fields of each block and of the stats are written out by the JSON output formats.
+/// An empty list means every field, so the default projection writes exactly what the models serialize to.
+#[derive(Debug, Clone, Default)]
+pub struct Projection {
+    block_fields: Vec<BlockField>,
+    stats_fields: Vec<StatsField>,
+}
+
+impl Projection {
+    /// Fields named more than once are only written once, where they were first named
+    pub fn new(block_fields:&[BlockField], stats_fields:&[StatsField]) -> Projection {
+        Projection {
+            block_fields: block_fields.iter().copied().unique().collect(),
+            stats_fields: stats_fields.iter().copied().unique().collect(),
+        }
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.block_fields.is_empty() && self.stats_fields.is_empty()
+    }
+
+    /// Whether the projected stats include the given field
+    pub fn writes_stats_field(&self, field:StatsField) -> bool {
+        self.stats_fields.is_empty() || self.stats_fields.contains(&field)
+    }
+
+    pub fn segment<'a>(&'a self, segment:&'a SummarisedContent<'a>) -> ProjectedSegment<'a> {
+        ProjectedSegment { segment, projection: self }
+    }
+
+    pub fn stats<'a>(&'a self, stats:&'a Stats<'a>) -> ProjectedStats<'a> {
+        ProjectedStats { stats, projection: self }
+    }
+}
+
+/// A block, serialized with only the fields picked by the projection
+#[derive(Debug)]
+pub struct ProjectedBlock<'a> {
+    block: &'a CapiBlock,
+    fields: &'a [BlockField],
+}
+
+impl Serialize for ProjectedBlock<'_> {
+    fn serialize<S:Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        if self.fields.is_empty() {
+            return self.block.serialize(serializer);
+        }
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for field in self.fields {
+            match field {
+                BlockField::Id=>map.serialize_entry("id", &self.block.id)?,
+                BlockField::BodyHtml=>map.serialize_entry("bodyHtml", &self.block.bodyHtml)?,
+                BlockField::BodyText=>map.serialize_entry("bodyText", &self.block.body_text())?,
+                BlockField::Attributes=>map.serialize_entry("attributes", &self.block.attributes)?,
+                BlockField::FirstPublishedDate=>map.serialize_entry("firstPublishedDate", &self.block.firstPublishedDate)?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// A segment whose blocks are serialized through the projection. The main block in `context` is left as it is.
+#[derive(Debug)]
+pub struct ProjectedSegment<'a> {
+    segment: &'a SummarisedContent<'a>,
+    projection: &'a Projection,
+}
+
+impl Serialize for ProjectedSegment<'_> {
+    fn serialize<S:Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        let fields = self.projection.block_fields.as_slice();
+        if fields.is_empty() {
+            return self.segment.serialize(serializer);
+        }
+        self.segment.layout(|block| ProjectedBlock { block, fields }).serialize(serializer)
+    }
+}
+
+/// The stats, serialized with only the fields picked by the projection
+#[derive(Debug)]
+pub struct ProjectedStats<'a> {
+    stats: &'a Stats<'a>,
+    projection: &'a Projection,
+}
+
+impl Serialize for ProjectedStats<'_> {
+    fn serialize<S:Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        let fields = self.projection.stats_fields.as_slice();
+        if fields.is_empty() {
+            return self.stats.serialize(serializer);
+        }
+        let mut map = serializer.serialize_map(Some(fields.len()))?;
+        for field in fields {
+            match field {
+                StatsField::OriginalId=>map.serialize_entry("original_id", self.stats.original_id)?,
+                StatsField::WebPublicationDate=>map.serialize_entry("web_publication_date", &self.stats.web_publication_date)?,
+                StatsField::RetrievedAt=>map.serialize_entry("retrieved_at", &self.stats.retrieved_at)?,
+                StatsField::SummaryBlockCount=>map.serialize_entry("summary_block_count", &self.stats.summary_block_count)?,
+                StatsField::TotalBlockCount=>map.serialize_entry("total_block_count", &self.stats.total_block_count)?,
+                StatsField::KeyEventBlockCount=>map.serialize_entry("key_event_block_count", &self.stats.key_event_block_count)?,
+                StatsField::KeywordTags=>map.serialize_entry("keyword_tags", &self.stats.keyword_tags)?,
+                StatsField::PinnedBlockIds=>map.serialize_entry("pinned_block_ids", &self.stats.pinned_block_ids)?,
+            }
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{block, stats};
+
+    #[test]
+    pub fn test_default_projection_is_unchanged() {
+        let blocks = [block("a").build(), block("b").key_event(true).build()];
+        let segment = SummarisedContent::new(&blocks[0], vec!(&blocks[1]));
+        let stats = stats("news/live/blog").block_counts(1, 3, 1).build();
+        let projection = Projection::default();
+
+        assert!(projection.is_everything());
+        assert_eq!(serde_json::to_string(&projection.segment(&segment)).unwrap(), serde_json::to_string(&segment).unwrap());
+        assert_eq!(serde_json::to_string(&projection.stats(&stats)).unwrap(), serde_json::to_string(&stats).unwrap());
+    }
+
+    #[test]
+    pub fn test_project_blocks() {
+        let blocks = [block("a").build(), block("b").key_event(true).build()];
+        let segment = SummarisedContent::new(&blocks[0], vec!(&blocks[1]));
+        let projection = Projection::new(&[BlockField::Id, BlockField::BodyText, BlockField::FirstPublishedDate], &[]);
+
+        let value = serde_json::to_value(projection.segment(&segment)).unwrap();
+        assert_eq!(value["summary"], serde_json::json!({"id": "a", "bodyText": "This is block a", "firstPublishedDate": "2022-01-02T03:04:05Z"}));
+        assert_eq!(value["events"][0], serde_json::json!({"id": "b", "bodyText": "This is block b", "firstPublishedDate": "2022-01-02T03:04:05Z"}));
+        assert_eq!(value["key_events"], serde_json::json!(["b"]));
+        assert!(value.get("context").is_none());
+
+        let empty = SummarisedContent::empty();
+        assert_eq!(serde_json::to_value(projection.segment(&empty)).unwrap()["summary"], serde_json::Value::Null);
+    }
+
+    #[test]
+    pub fn test_projected_segment_keeps_every_key() {
+        let blocks = [block("a").build(), block("b").key_event(true).build()];
+        let main = block("main").main();
+        let mut segment = SummarisedContent::new(&blocks[0], vec!(&blocks[1]));
+        segment.context = Some(MainContent { main: &main, standfirst: Some("standfirst") });
+        segment.duplicate_blocks.push("b");
+        let projection = Projection::new(&[BlockField::Id], &[]);
+
+        let default = serde_json::to_value(&segment).unwrap();
+        let projected = serde_json::to_value(projection.segment(&segment)).unwrap();
+        let keys = |v:&serde_json::Value| v.as_object().unwrap().keys().cloned().collect::<Vec<String>>();
+        assert_eq!(keys(&projected), keys(&default));
+        assert_eq!(projected["duplicate_blocks"], default["duplicate_blocks"]);
+    }
+
+    #[test]
+    pub fn test_project_stats() {
+        let stats = stats("news/live/blog").block_counts(1, 3, 1).build();
+        let projection = Projection::new(&[], &[StatsField::OriginalId, StatsField::TotalBlockCount]);
+
+        let value = serde_json::to_value(projection.stats(&stats)).unwrap();
+        assert_eq!(value, serde_json::json!({"original_id": "news/live/blog", "total_block_count": 3}));
+    }
+
+    #[test]
+    pub fn test_repeated_fields_written_once() {
+        let blocks = [block("a").build()];
+        let segment = SummarisedContent::new(&blocks[0], vec!());
+        let projection = Projection::new(&[BlockField::Id, BlockField::BodyText, BlockField::Id], &[StatsField::OriginalId, StatsField::OriginalId]);
+
+        assert_eq!(serde_json::to_string(&projection.segment(&segment)).unwrap(), "{\"summary\":{\"id\":\"a\",\"bodyText\":\"This is block a\"},\"events\":[],\"key_events\":[]}");
+        let stats = stats("news/live/blog").build();
+        assert_eq!(serde_json::to_string(&projection.stats(&stats)).unwrap(), "{\"original_id\":\"news/live/blog\"}");
+    }
+}
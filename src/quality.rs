@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::read_to_string;
+use std::path::Path;
+use serde::Serialize;
+use crate::file_store::write_file_atomically;
+use crate::models::*;
+use crate::text::{is_embed_only, normalise_words};
+
+/// Name of the report of rejected segments, written at the top of the output path
+pub const REJECTS_FILE:&str = "REJECTS.jsonl";
+
+/// Phrases that liveblogs open and close with, which say nothing about the news
+pub const DEFAULT_BOILERPLATE:[&str; 24] = [
+    "good morning", "good afternoon", "good evening", "hello and welcome", "welcome to", "welcome back",
+    "live coverage", "live blog", "liveblog", "live updates", "thanks for joining us", "thank you for joining us",
+    "we'll bring you", "i'll be bringing you", "stay with us", "follow along", "as they happen", "the latest developments",
+    "this blog is now closed", "we're closing this blog", "thanks for reading", "thank you for reading",
+    "that's all from us", "that's it from me",
+];
+
+/// A summary that contains a boilerplate phrase is only rejected if fewer than this many words are left once the
+/// phrases are taken out, so that a real summary which happens to start with "Good morning" is kept
+const BOILERPLATE_SPARE_WORDS:usize = 12;
+
+/// Something wrong with a segment that makes it no use for training
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum QualityIssue {
+    SummaryTooShort { characters: usize, minimum: usize },
+    TooFewEvents { events: usize, minimum: usize },
+    SummaryRatioTooLow { ratio: f64, minimum: f64 },
+    SummaryRatioTooHigh { ratio: f64, maximum: f64 },
+    Boilerplate { phrase: String },
+    EmbedOnly,
+}
+
+impl QualityIssue {
+    /// The name of the rule, as it appears in the rejects report
+    pub fn rule(&self) -> &'static str {
+        match self {
+            QualityIssue::SummaryTooShort { .. }=>"summary_too_short",
+            QualityIssue::TooFewEvents { .. }=>"too_few_events",
+            QualityIssue::SummaryRatioTooLow { .. }=>"summary_ratio_too_low",
+            QualityIssue::SummaryRatioTooHigh { .. }=>"summary_ratio_too_high",
+            QualityIssue::Boilerplate { .. }=>"boilerplate",
+            QualityIssue::EmbedOnly=>"embed_only",
+        }
+    }
+}
+
+/// The rules that segments are checked against. A rule that is None, empty or false is not checked.
+/// Lengths are in characters of plain text, and the ratio is the length of the summary over the total length of the events.
+#[derive(Clone, Debug, Default)]
+pub struct QualityRules {
+    pub min_summary_characters: Option<usize>,
+    /// Events that are only embeds are not counted
+    pub min_events: Option<usize>,
+    pub min_summary_ratio: Option<f64>,
+    pub max_summary_ratio: Option<f64>,
+    pub boilerplate_phrases: Vec<String>,
+    pub reject_embed_only: bool,
+}
+
+impl QualityRules {
+    pub fn is_empty(&self) -> bool {
+        self.min_summary_characters.is_none() && self.min_events.is_none() && self.min_summary_ratio.is_none()
+            && self.max_summary_ratio.is_none() && self.boilerplate_phrases.is_empty() && !self.reject_embed_only
+    }
+
+    /// Returns everything wrong with the segment; it passes if this is empty
+    pub fn check(&self, segment:&SummarisedContent) -> Vec<QualityIssue> {
+        let mut issues = vec!();
+        let summary_text = segment.summary.map(|s| s.body_text()).unwrap_or_default();
+        let summary_characters = summary_text.chars().count();
+        let event_texts:Vec<String> = segment.events.iter().map(|e| e.body_text()).collect();
+
+        if let Some(minimum) = self.min_summary_characters {
+            if summary_characters < minimum {
+                issues.push(QualityIssue::SummaryTooShort { characters: summary_characters, minimum });
+            }
+        }
+        if let Some(minimum) = self.min_events {
+            let events = event_texts.iter().filter(|t| !t.is_empty()).count();
+            if events < minimum {
+                issues.push(QualityIssue::TooFewEvents { events, minimum });
+            }
+        }
+
+        let event_characters:usize = event_texts.iter().map(|t| t.chars().count()).sum();
+        if segment.summary.is_some() && event_characters > 0 {
+            let ratio = summary_characters as f64 / event_characters as f64;
+            if let Some(minimum) = self.min_summary_ratio.filter(|m| ratio < *m) {
+                issues.push(QualityIssue::SummaryRatioTooLow { ratio, minimum });
+            }
+            if let Some(maximum) = self.max_summary_ratio.filter(|m| ratio > *m) {
+                issues.push(QualityIssue::SummaryRatioTooHigh { ratio, maximum });
+            }
+        }
+
+        if let Some(phrase) = find_boilerplate(&summary_text, &self.boilerplate_phrases) {
+            issues.push(QualityIssue::Boilerplate { phrase: phrase.to_owned() });
+        }
+        if self.reject_embed_only && segment.summary.is_some_and(|s| is_embed_only(&s.bodyHtml)) {
+            issues.push(QualityIssue::EmbedOnly);
+        }
+        issues
+    }
+}
+
+/// Returns the first of the phrases that the text contains, if the phrases make up nearly all of it
+fn find_boilerplate<'p>(text:&str, phrases:&'p [String]) -> Option<&'p str> {
+    let mut rest = format!(" {} ", normalise_words(text));
+    let mut found = None;
+    for phrase in phrases {
+        let padded = format!(" {} ", normalise_words(phrase));
+        if padded.trim().is_empty() {
+            continue;
+        }
+        while rest.contains(&padded) {
+            found.get_or_insert(phrase.as_str());
+            rest = rest.replacen(&padded, " ", 1);
+        }
+    }
+    found.filter(|_| rest.split_whitespace().count() < BOILERPLATE_SPARE_WORDS)
+}
+
+/// Reads boilerplate phrases from a file with one on each line. Blank lines and lines starting with `#` are ignored.
+pub fn load_boilerplate_phrases(path:&str) -> Result<Vec<String>, Box<dyn Error>> {
+    let content = read_to_string(path).map_err(|e| format!("unable to read boilerplate phrases from {}: {}", path, e))?;
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_owned())
+        .collect())
+}
+
+/// A segment that was left out, and why
+#[derive(Debug, Serialize)]
+pub struct RejectedSegment {
+    pub liveblog_id: String,
+    pub segment_index: usize,
+    pub summary_block_id: Option<String>,
+    pub event_block_ids: Vec<String>,
+    pub reasons: Vec<QualityIssue>,
+}
+
+/// Every segment rejected during a harvest, written out as one JSON line each
+#[derive(Debug, Default)]
+pub struct RejectsReport {
+    pub rejected: Vec<RejectedSegment>,
+}
+
+impl RejectsReport {
+    /// How many rejected segments broke each rule. A segment that broke several rules is counted under each of them.
+    pub fn counts_by_rule(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for issue in self.rejected.iter().flat_map(|r| r.reasons.iter()) {
+            *counts.entry(issue.rule()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn write(&self, base_path:&Path) -> Result<(), Box<dyn Error>> {
+        let mut content:Vec<u8> = vec!();
+        for rejected in self.rejected.iter() {
+            serde_json::to_writer(&mut content, rejected)?;
+            content.push(b'\n');
+        }
+        write_file_atomically(&base_path.join(REJECTS_FILE), &content)
+    }
+}
+
+/// Drops the segments of a liveblog that break any of the rules, adding them to the report.
+/// Segment indexes in the report are positions in `segments` before anything was dropped.
+pub fn filter_segments(liveblog_id:&str, segments:&mut Vec<SummarisedContent>, rules:&QualityRules, report:&mut RejectsReport) {
+    let mut index = 0;
+    segments.retain(|segment| {
+        let reasons = rules.check(segment);
+        let segment_index = index;
+        index += 1;
+        if reasons.is_empty() {
+            return true;
+        }
+        report.rejected.push(RejectedSegment {
+            liveblog_id: liveblog_id.to_owned(),
+            segment_index,
+            summary_block_id: segment.summary.map(|s| s.id.to_owned()),
+            event_block_ids: segment.events.iter().map(|e| e.id.to_owned()).collect(),
+            reasons,
+        });
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::block;
+
+    fn default_phrases() -> Vec<String> {
+        DEFAULT_BOILERPLATE.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    pub fn test_boilerplate() {
+        let phrases = default_phrases();
+        assert_eq!(find_boilerplate("Good morning, and welcome to today’s live coverage of the day's events.", &phrases), Some("good morning"));
+        assert_eq!(find_boilerplate("Thanks for reading. This blog is now closed.", &phrases), Some("this blog is now closed"));
+        assert_eq!(find_boilerplate("Good morning. Here is what we know so far: the prime minister has resigned after losing a vote of confidence in parliament", &phrases), None);
+        assert_eq!(find_boilerplate("Welcomes to the new stadium were muted", &phrases), None);
+    }
+
+    #[test]
+    pub fn test_check_rules() {
+        let rules = QualityRules {
+            min_summary_characters: Some(20),
+            min_events: Some(2),
+            max_summary_ratio: Some(1.0),
+            boilerplate_phrases: default_phrases(),
+            reject_embed_only: true,
+            ..QualityRules::default()
+        };
+        let events = [
+            block("a").html("<p>The minister arrived at the summit this morning.</p>").summary(true).build(),
+            block("b").html("<p>Talks began shortly afterwards.</p>").summary(true).build(),
+            block("tweet").html(r#"<figure class="element-tweet"><blockquote>Tweet</blockquote></figure>"#).summary(true).build(),
+        ];
+
+        let good = block("good").html("<p>The minister arrived and talks began.</p>").summary(true).build();
+        assert!(rules.check(&SummarisedContent::new(&good, events.iter().collect())).is_empty());
+
+        let greeting = block("greeting").html("<p>Good morning and welcome to our live coverage.</p>").summary(true).build();
+        let issues = rules.check(&SummarisedContent::new(&greeting, events[..1].iter().collect()));
+        assert_eq!(issues.iter().map(|i| i.rule()).collect::<Vec<_>>(), vec!("too_few_events", "boilerplate"));
+
+        let embed = block("embed").html(r#"<figure class="element-image"><img src="x.jpg"/></figure>"#).summary(true).build();
+        let issues = rules.check(&SummarisedContent::new(&embed, events.iter().collect()));
+        assert_eq!(issues, vec!(QualityIssue::SummaryTooShort { characters: 0, minimum: 20 }, QualityIssue::EmbedOnly));
+
+        let long = block("long").html(&format!("<p>{}</p>", "Much longer than the events. ".repeat(10))).summary(true).build();
+        let issues = rules.check(&SummarisedContent::new(&long, events.iter().collect()));
+        assert_eq!(issues.iter().map(|i| i.rule()).collect::<Vec<_>>(), vec!("summary_ratio_too_high"));
+        assert!(QualityRules::default().check(&SummarisedContent::new(&embed, vec!())).is_empty());
+    }
+
+    #[test]
+    pub fn test_filter_and_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = QualityRules { min_events: Some(1), ..QualityRules::default() };
+        let summary = block("summary").html("<p>Summary</p>").summary(true).build();
+        let event = block("event").html("<p>Event</p>").summary(true).build();
+        let mut segments = vec!(
+            SummarisedContent::new(&summary, vec!()),
+            SummarisedContent::new(&summary, vec!(&event)),
+        );
+
+        let mut report = RejectsReport::default();
+        filter_segments("news/live/blog", &mut segments, &rules, &mut report);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].events[0].id, "event");
+        assert_eq!(report.counts_by_rule().get("too_few_events"), Some(&1));
+
+        report.write(dir.path()).unwrap();
+        let content = std::fs::read_to_string(dir.path().join(REJECTS_FILE)).unwrap();
+        let line:serde_json::Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert_eq!(line["liveblog_id"], "news/live/blog");
+        assert_eq!(line["segment_index"], 0);
+        assert_eq!(line["summary_block_id"], "summary");
+        assert_eq!(line["reasons"][0]["rule"], "too_few_events");
+        assert_eq!(line["reasons"][0]["minimum"], 1);
+    }
+
+    #[test]
+    pub fn test_load_boilerplate_phrases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("phrases.txt");
+        std::fs::write(&path, "# greetings\nGood morning\n\n  welcome back  \n").unwrap();
+        assert_eq!(load_boilerplate_phrases(path.to_str().unwrap()).unwrap(), vec!("Good morning", "welcome back"));
+        assert!(load_boilerplate_phrases(dir.path().join("missing.txt").to_str().unwrap()).is_err());
+    }
+}
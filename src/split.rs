@@ -0,0 +1,236 @@
+use std::error::Error;
+use std::path::PathBuf;
+use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+use crate::models::*;
+use crate::writer::OutputSink;
+use crate::manifest::ManifestFile;
+
+/// The sets that a dataset is split into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Split {
+    Train,
+    Validation,
+    Test,
+}
+
+impl Split {
+    pub const ALL:[Split; 3] = [Split::Train, Split::Validation, Split::Test];
+
+    /// The name of the directory or key prefix that this split is written under
+    pub fn name(&self) -> &'static str {
+        match self {
+            Split::Train=>"train",
+            Split::Validation=>"validation",
+            Split::Test=>"test",
+        }
+    }
+}
+
+/// How liveblogs are divided between the splits. Every segment of a liveblog goes to the same split, so nothing from
+/// one liveblog can leak from training into testing.
+#[derive(Clone, Debug)]
+pub struct SplitConfig {
+    /// Relative sizes of the train, validation and test splits; they do not need to add up to 1
+    pub ratios: [f64; 3],
+    /// If given, liveblogs published on or after this date all go to the test split and the rest are divided between
+    /// train and validation, so the test set is strictly later in time than anything trained on
+    pub test_from: Option<NaiveDate>,
+}
+
+impl SplitConfig {
+    pub fn new(ratios:&[f64], test_from:Option<NaiveDate>) -> Result<SplitConfig, Box<dyn Error>> {
+        let ratios:[f64; 3] = ratios.try_into().map_err(|_| "three split ratios are needed, for train, validation and test")?;
+        if ratios.iter().any(|r| !r.is_finite() || *r < 0.0) {
+            return Err("split ratios cannot be negative".into());
+        }
+        let needed = if test_from.is_some() { &ratios[..2] } else { &ratios[..] };
+        if needed.iter().sum::<f64>() <= 0.0 {
+            return Err("at least one split ratio must be more than 0".into());
+        }
+        Ok(SplitConfig { ratios, test_from })
+    }
+
+    /// Decides which split a liveblog belongs to. This only depends on its id and publication date, so a liveblog
+    /// always goes to the same split however many times it is harvested and whatever else is in the run.
+    pub fn assign(&self, capi_id:&str, published_on:NaiveDate) -> Split {
+        let candidates:&[Split] = match self.test_from {
+            Some(test_from) if published_on>=test_from=>return Split::Test,
+            Some(_)=>&Split::ALL[..2],
+            None=>&Split::ALL,
+        };
+        let ratios = &self.ratios[..candidates.len()];
+        let total:f64 = ratios.iter().sum();
+
+        let position = stable_fraction(capi_id) * total;
+        let mut cumulative = 0.0;
+        for (split, ratio) in candidates.iter().zip(ratios) {
+            cumulative += ratio;
+            if position < cumulative {
+                return *split;
+            }
+        }
+        //only reached through rounding, and only by something that belongs at the end
+        candidates.iter().zip(ratios).rev().find(|(_, r)| **r > 0.0).map(|(s, _)| *s).unwrap_or(Split::Train)
+    }
+}
+
+/// Maps a string to a number in [0, 1) using SHA-256, which is the same on every platform and in every release,
+/// unlike the standard library's hasher
+fn stable_fraction(s:&str) -> f64 {
+    let digest = Sha256::digest(s.as_bytes());
+    let mut first = [0u8; 8];
+    first.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(first) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Sends each liveblog to the sink for its split, as decided by a `SplitConfig` when the liveblog begins
+pub struct SplitSink {
+    config: SplitConfig,
+    sinks: Vec<(Split, Box<dyn OutputSink>)>,
+    counts: [usize; 3],
+    current: Option<usize>,
+}
+
+impl SplitSink {
+    /// `make_sink` is called once for each split, to make the sink that split is written to
+    pub fn new(config:SplitConfig, make_sink:impl Fn(Split) -> Result<Box<dyn OutputSink>, Box<dyn Error>>) -> Result<SplitSink, Box<dyn Error>> {
+        let sinks = Split::ALL.iter()
+            .map(|split| make_sink(*split).map(|sink| (*split, sink)))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        Ok(SplitSink { config, sinks, counts: [0; 3], current: None })
+    }
+
+    fn current_sink(&mut self) -> Result<&mut Box<dyn OutputSink>, Box<dyn Error>> {
+        let idx = self.current.ok_or("write called before begin_liveblog")?;
+        Ok(&mut self.sinks[idx].1)
+    }
+}
+
+impl OutputSink for SplitSink {
+    fn begin_liveblog(&mut self, main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        let split = self.config.assign(stats.original_id, stats.web_publication_date.date_naive());
+        let idx = self.sinks.iter().position(|(s, _)| *s==split).ok_or("no sink for split")?;
+        self.current = Some(idx);
+        self.counts[idx] += 1;
+        self.sinks[idx].1.begin_liveblog(main, stats)
+    }
+
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+        self.current_sink()?.write_segment(index, segment)
+    }
+
+    fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        self.current_sink()?.write_stats(stats)
+    }
+
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.current_sink()?.finish_liveblog()?;
+        self.current = None;
+        Ok(())
+    }
+
+    fn abort_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.current.take() {
+            Some(idx)=>self.sinks[idx].1.abort_liveblog(),
+            None=>Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        for (_, sink) in self.sinks.iter_mut() {
+            sink.flush()?;
+        }
+        let counts:Vec<String> = self.sinks.iter().zip(self.counts).map(|((s, _), c)| format!("{} {}", c, s.name())).collect();
+        eprintln!("INFO split liveblogs into {}", counts.join(", "));
+        Ok(())
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        self.sinks.iter().flat_map(|(_, sink)| sink.output_files()).collect()
+    }
+
+    fn writes_through(&self) -> bool {
+        self.sinks.iter().all(|(_, sink)| sink.writes_through())
+    }
+
+    fn uploaded_objects(&self) -> Vec<ManifestFile> {
+        self.sinks.iter().flat_map(|(_, sink)| sink.uploaded_objects()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{write_liveblog, DirectorySink};
+    use crate::file_store::LooseFiles;
+    use crate::test_fixtures::{block, stats};
+
+    fn date(s:&str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    pub fn test_assign_is_stable_and_follows_ratios() {
+        let config = SplitConfig::new(&[0.8, 0.1, 0.1], None).unwrap();
+        let ids:Vec<String> = (0..2000).map(|i| format!("news/live/2023/oct/13/blog-{}", i)).collect();
+
+        let mut counts = [0usize; 3];
+        for id in ids.iter() {
+            let split = config.assign(id, date("2023-10-13"));
+            assert_eq!(split, config.assign(id, date("2023-10-13")));
+            counts[Split::ALL.iter().position(|s| *s==split).unwrap()] += 1;
+        }
+        assert!(counts[0] > 1500 && counts[0] < 1700, "{:?}", counts);
+        assert!(counts[1] > 140 && counts[1] < 260, "{:?}", counts);
+        assert!(counts[2] > 140 && counts[2] < 260, "{:?}", counts);
+
+        //the hash must never change between releases, or liveblogs would move between splits
+        assert_eq!(stable_fraction("news/live/2023/oct/13/blog-0"), 0.3682400841529284);
+        let everything_to_test = SplitConfig::new(&[0.0, 0.0, 1.0], None).unwrap();
+        assert_eq!(everything_to_test.assign("news/live/2023/oct/13/blog-0", date("2023-10-13")), Split::Test);
+    }
+
+    #[test]
+    pub fn test_assign_by_date() {
+        let config = SplitConfig::new(&[0.9, 0.1, 0.0], Some(date("2023-10-01"))).unwrap();
+        for i in 0..200 {
+            let id = format!("news/live/blog-{}", i);
+            assert_eq!(config.assign(&id, date("2023-10-01")), Split::Test);
+            assert_ne!(config.assign(&id, date("2023-09-30")), Split::Test);
+        }
+    }
+
+    #[test]
+    pub fn test_bad_ratios() {
+        assert!(SplitConfig::new(&[0.8, 0.2], None).is_err());
+        assert!(SplitConfig::new(&[0.8, -0.1, 0.3], None).is_err());
+        assert!(SplitConfig::new(&[0.0, 0.0, 0.0], None).is_err());
+        assert!(SplitConfig::new(&[0.0, 0.0, 1.0], Some(date("2023-10-01"))).is_err());
+    }
+
+    #[test]
+    pub fn test_split_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dir.path().to_owned();
+        let config = SplitConfig::new(&[1.0, 0.0, 0.0], Some(date("2023-10-01"))).unwrap();
+        let mut sink = SplitSink::new(config, |split| {
+            let path = base_path.join(split.name());
+            Ok(Box::new(DirectorySink::new(Box::new(LooseFiles::new(path.to_str().unwrap(), false)))) as Box<dyn OutputSink>)
+        }).unwrap();
+
+        let summary = block("block").summary(true).published(Some("2023-09-02T03:04:05Z")).build();
+        let main = block("main").main();
+        let segments = vec!(SummarisedContent::new(&summary, vec!()));
+        for (id, published) in [("news/live/old", "2023-09-02T03:04:05Z"), ("news/live/new", "2023-10-02T03:04:05Z")] {
+            let stats = stats(id).published(published).block_counts(1, 1, 0).build();
+            write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats).unwrap();
+        }
+        sink.flush().unwrap();
+
+        assert!(dir.path().join("train/news/live/old/META.json").exists());
+        assert!(dir.path().join("test/news/live/new/META.json").exists());
+        assert!(!dir.path().join("train/news/live/new").exists());
+        assert!(sink.output_files().iter().any(|f| f.starts_with(dir.path().join("test"))));
+    }
+}
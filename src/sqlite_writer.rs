@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::path::PathBuf;
+use rusqlite::{params, Connection};
+use crate::models::*;
+use crate::writer::OutputSink;
+
+/// The schema is created if it does not exist, so the same database can be re-used between harvests.
+/// Segments cascade-delete their blocks, and a liveblog's segments are replaced wholesale whenever it is harvested again.
+const SCHEMA:&str = "
+    PRAGMA foreign_keys = ON;
+
+    CREATE TABLE IF NOT EXISTS liveblogs (
+        id TEXT PRIMARY KEY,
+        web_publication_date TEXT NOT NULL,
+        retrieved_at TEXT NOT NULL,
+        summary_block_count INTEGER,
+        total_block_count INTEGER,
+        key_event_block_count INTEGER,
+        standfirst TEXT,
+        main_block TEXT
+    );
+    CREATE INDEX IF NOT EXISTS liveblogs_publication_date ON liveblogs(web_publication_date);
+
+    CREATE TABLE IF NOT EXISTS segments (
+        liveblog_id TEXT NOT NULL REFERENCES liveblogs(id) ON DELETE CASCADE,
+        segment_index INTEGER NOT NULL,
+        summary_block_id TEXT,
+        PRIMARY KEY (liveblog_id, segment_index)
+    );
+
+    CREATE TABLE IF NOT EXISTS blocks (
+        id TEXT NOT NULL,
+        liveblog_id TEXT NOT NULL,
+        segment_index INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        is_summary INTEGER NOT NULL,
+        is_key_event INTEGER NOT NULL,
+        is_pinned INTEGER NOT NULL,
+        title TEXT,
+        body_html TEXT NOT NULL,
+        first_published_date TEXT,
+        body_text TEXT,
+        PRIMARY KEY (liveblog_id, segment_index, id),
+        FOREIGN KEY (liveblog_id, segment_index) REFERENCES segments(liveblog_id, segment_index) ON DELETE CASCADE
+    );
+    CREATE INDEX IF NOT EXISTS blocks_segment ON blocks(liveblog_id, segment_index);
+    CREATE INDEX IF NOT EXISTS blocks_publication_date ON blocks(first_published_date);
+
+    CREATE TABLE IF NOT EXISTS tags (
+        id TEXT PRIMARY KEY,
+        web_title TEXT NOT NULL,
+        type TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS liveblog_tags (
+        liveblog_id TEXT NOT NULL REFERENCES liveblogs(id) ON DELETE CASCADE,
+        tag_id TEXT NOT NULL REFERENCES tags(id),
+        PRIMARY KEY (liveblog_id, tag_id)
+    );
+    CREATE INDEX IF NOT EXISTS liveblog_tags_tag ON liveblog_tags(tag_id);
+";
+
+/// Writes liveblogs, their segments, blocks and keyword tags into normalised tables in a SQLite database.
+/// Each liveblog is written in its own transaction, upserting the liveblog and tags and replacing its segments,
+/// so re-harvesting a liveblog updates it in place.
+pub struct SqliteSink {
+    conn: Connection,
+    database_path: PathBuf,
+    liveblog_id: Option<String>,
+}
+
+impl SqliteSink {
+    pub fn new(database_path:&str) -> Result<SqliteSink, Box<dyn Error>> {
+        let conn = Connection::open(database_path)?;
+        conn.execute_batch(SCHEMA)?;
+        eprintln!("DEBUG writing to database {}", database_path);
+        Ok(SqliteSink { conn, database_path: PathBuf::from(database_path), liveblog_id: None })
+    }
+
+    fn current_id(&self) -> Result<&str, Box<dyn Error>> {
+        self.liveblog_id.as_deref().ok_or_else(|| "write called before begin_liveblog".into())
+    }
+
+    fn insert_block(&self, liveblog_id:&str, segment_index:usize, position:usize, block:&CapiBlock) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT INTO blocks (id, liveblog_id, segment_index, position, is_summary, is_key_event, is_pinned, title, body_html, first_published_date, body_text)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+             ON CONFLICT (liveblog_id, segment_index, id) DO UPDATE SET
+                position=excluded.position, is_summary=excluded.is_summary,
+                is_key_event=excluded.is_key_event, is_pinned=excluded.is_pinned, title=excluded.title,
+                body_html=excluded.body_html, first_published_date=excluded.first_published_date, body_text=excluded.body_text",
+            params![
+                block.id,
+                liveblog_id,
+                segment_index,
+                position,
+                block.attributes.summary.unwrap_or(false),
+                block.is_key_event(),
+                block.attributes.pinned.unwrap_or(false),
+                block.attributes.title,
+                block.bodyHtml,
+                block.firstPublishedDate,
+                block.body_text(),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl OutputSink for SqliteSink {
+    fn begin_liveblog(&mut self, main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        self.conn.execute_batch("BEGIN")?;
+
+        self.conn.execute(
+            "INSERT INTO liveblogs (id, web_publication_date, retrieved_at, standfirst, main_block)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (id) DO UPDATE SET
+                web_publication_date=excluded.web_publication_date, retrieved_at=excluded.retrieved_at,
+                standfirst=excluded.standfirst, main_block=excluded.main_block",
+            params![
+                stats.original_id,
+                stats.web_publication_date.to_rfc3339(),
+                stats.retrieved_at.to_rfc3339(),
+                main.standfirst,
+                serde_json::to_string(main.main)?,
+            ],
+        )?;
+        self.conn.execute("DELETE FROM segments WHERE liveblog_id=?1", params![stats.original_id])?;
+        self.conn.execute("DELETE FROM liveblog_tags WHERE liveblog_id=?1", params![stats.original_id])?;
+
+        for tag in stats.keyword_tags.iter() {
+            self.conn.execute(
+                "INSERT INTO tags (id, web_title, type) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET web_title=excluded.web_title, type=excluded.type",
+                params![tag.id, tag.webTitle, tag.r#type],
+            )?;
+            self.conn.execute(
+                "INSERT OR IGNORE INTO liveblog_tags (liveblog_id, tag_id) VALUES (?1, ?2)",
+                params![stats.original_id, tag.id],
+            )?;
+        }
+
+        self.liveblog_id = Some(stats.original_id.to_owned());
+        Ok(())
+    }
+
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+        let liveblog_id = self.current_id()?;
+        self.conn.execute(
+            "INSERT INTO segments (liveblog_id, segment_index, summary_block_id) VALUES (?1, ?2, ?3)",
+            params![liveblog_id, index, segment.summary.map(|s| &s.id)],
+        )?;
+
+        let mut position = 0;
+        if let Some(summary) = segment.summary {
+            self.insert_block(liveblog_id, index, position, summary)?;
+            position += 1;
+        }
+        for event in segment.events.iter() {
+            self.insert_block(liveblog_id, index, position, event)?;
+            position += 1;
+        }
+        Ok(())
+    }
+
+    fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE liveblogs SET summary_block_count=?2, total_block_count=?3, key_event_block_count=?4 WHERE id=?1",
+            params![stats.original_id, stats.summary_block_count, stats.total_block_count, stats.key_event_block_count],
+        )?;
+        Ok(())
+    }
+
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.conn.execute_batch("COMMIT")?;
+        self.liveblog_id = None;
+        Ok(())
+    }
+
+    /// Rolls back the liveblog's transaction, so that the copy from any earlier harvest is left as it was
+    fn abort_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.liveblog_id = None;
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        //if a liveblog was left half-written then roll it back rather than committing partial content
+        self.abort_liveblog()
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        vec!(self.database_path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{block, keyword_tag, stats};
+    use crate::writer::write_liveblog;
+
+    fn count(conn:&Connection, sql:&str) -> i64 {
+        conn.query_row(sql, [], |r| r.get(0)).unwrap()
+    }
+
+    #[test]
+    pub fn test_write_and_reharvest() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("test.sqlite");
+        let mut sink = SqliteSink::new(db_path.to_str().unwrap()).unwrap();
+        let main = block("main").main();
+        let main_content = MainContent { main: &main, standfirst: Some("Standfirst") };
+
+        let blocks = [block("a").build(), block("b").summary(true).build(), block("c").build(), block("d").build()];
+        let segments = vec!(
+            SummarisedContent { summary: None, events: vec!(&blocks[0]), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() },
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2], &blocks[3])),
+        );
+        write_liveblog(&mut sink, &segments, &main_content, &stats("news/live/blog").tags(vec!(keyword_tag("t1"), keyword_tag("t2"))).build()).unwrap();
+
+        //harvesting again with different segmentation and tags should replace, not duplicate
+        let segments = vec!(
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2])),
+        );
+        write_liveblog(&mut sink, &segments, &main_content, &stats("news/live/blog").tags(vec!(keyword_tag("t2"))).build()).unwrap();
+        sink.flush().unwrap();
+
+        let conn = &sink.conn;
+        assert_eq!(count(conn, "SELECT COUNT(*) FROM liveblogs"), 1);
+        assert_eq!(count(conn, "SELECT COUNT(*) FROM segments"), 1);
+        assert_eq!(count(conn, "SELECT COUNT(*) FROM blocks"), 2);
+        assert_eq!(count(conn, "SELECT COUNT(*) FROM blocks WHERE is_summary=1"), 1);
+        assert_eq!(count(conn, "SELECT COUNT(*) FROM tags"), 2);
+        assert_eq!(count(conn, "SELECT COUNT(*) FROM liveblog_tags"), 1);
+        assert_eq!(count(conn, "SELECT total_block_count FROM liveblogs WHERE id='news/live/blog'"), 3);
+        let standfirst:String = conn.query_row("SELECT standfirst FROM liveblogs", [], |r| r.get(0)).unwrap();
+        assert_eq!(standfirst, "Standfirst");
+        let body_text:String = conn.query_row("SELECT body_text FROM blocks WHERE id='b'", [], |r| r.get(0)).unwrap();
+        assert_eq!(body_text, "This is block b");
+    }
+
+    #[test]
+    pub fn test_block_in_two_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = SqliteSink::new(dir.path().join("test.sqlite").to_str().unwrap()).unwrap();
+        let main = block("main").main();
+        let blocks = [block("a").summary(true).build(), block("b").summary(true).build(), block("shared").build()];
+        let segments = vec!(SummarisedContent::new(&blocks[0], vec!(&blocks[2])), SummarisedContent::new(&blocks[1], vec!(&blocks[2])));
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").tags(vec!()).build()).unwrap();
+
+        assert_eq!(count(&sink.conn, "SELECT COUNT(*) FROM blocks WHERE id='shared'"), 2);
+        assert_eq!(count(&sink.conn, "SELECT SUM(segment_index) FROM blocks WHERE id='shared'"), 1);
+    }
+
+    #[test]
+    pub fn test_failed_liveblog_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = SqliteSink::new(dir.path().join("test.sqlite").to_str().unwrap()).unwrap();
+        sink.conn.execute_batch("CREATE TEMP TRIGGER fail_block BEFORE INSERT ON blocks WHEN NEW.id='broken' BEGIN SELECT RAISE(ABORT, 'broken block'); END").unwrap();
+        let main = block("main").main();
+        let main_content = MainContent { main: &main, standfirst: None };
+        let blocks = [block("a").summary(true).build(), block("broken").build()];
+
+        write_liveblog(&mut sink, &[SummarisedContent::new(&blocks[0], vec!())], &main_content, &stats("news/live/first").tags(vec!()).build()).unwrap();
+        let result = write_liveblog(&mut sink, &[SummarisedContent::new(&blocks[0], vec!(&blocks[1]))], &main_content, &stats("news/live/second").tags(vec!(keyword_tag("t1"))).build());
+        assert!(result.unwrap_err().to_string().contains("broken block"));
+
+        //nothing of the failed liveblog is left, and the next one goes into its own transaction
+        assert!(sink.conn.is_autocommit());
+        assert_eq!(count(&sink.conn, "SELECT COUNT(*) FROM liveblogs WHERE id='news/live/second'"), 0);
+        assert_eq!(count(&sink.conn, "SELECT COUNT(*) FROM liveblog_tags"), 0);
+        write_liveblog(&mut sink, &[SummarisedContent::new(&blocks[0], vec!())], &main_content, &stats("news/live/third").tags(vec!()).build()).unwrap();
+        assert_eq!(count(&sink.conn, "SELECT COUNT(*) FROM liveblogs"), 2);
+    }
+
+    #[test]
+    pub fn test_reopen_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("test.sqlite");
+        let main = block("main").main();
+        let blocks = [block("a").summary(true).build()];
+        let segments = vec!(SummarisedContent::new(&blocks[0], vec!()));
+
+        {
+            let mut sink = SqliteSink::new(db_path.to_str().unwrap()).unwrap();
+            write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/first").tags(vec!()).build()).unwrap();
+        }
+
+        let mut sink = SqliteSink::new(db_path.to_str().unwrap()).unwrap();
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/second").tags(vec!()).build()).unwrap();
+        assert_eq!(count(&sink.conn, "SELECT COUNT(*) FROM liveblogs"), 2);
+        assert_eq!(count(&sink.conn, "SELECT COUNT(*) FROM blocks"), 2);
+    }
+}
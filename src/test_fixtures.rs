@@ -0,0 +1,109 @@
+use chrono::DateTime;
+use crate::models::*;
+
+/// Builds a `CapiBlock`. It starts out as an ordinary block, neither summary nor pinned, published at
+/// 2022-01-02T03:04:05Z, whose HTML is "<p>This is block {id}</p>".
+pub struct BlockBuilder {
+    block: CapiBlock,
+}
+
+pub fn block(id:&str) -> BlockBuilder {
+    BlockBuilder {
+        block: CapiBlock {
+            id: id.to_owned(),
+            bodyHtml: format!("<p>This is block {}</p>", id),
+            attributes: CapiBlockAttributes { summary: Some(false), title: None, pinned: Some(false), keyEvent: None },
+            firstPublishedDate: Some("2022-01-02T03:04:05Z".to_owned()),
+        },
+    }
+}
+
+impl BlockBuilder {
+    pub fn html(mut self, html:&str) -> BlockBuilder {
+        self.block.bodyHtml = html.to_owned();
+        self
+    }
+
+    /// Sets the HTML to a single paragraph of `text`
+    pub fn text(self, text:&str) -> BlockBuilder {
+        self.html(&format!("<p>{}</p>", text))
+    }
+
+    pub fn summary(mut self, summary:bool) -> BlockBuilder {
+        self.block.attributes.summary = Some(summary);
+        self
+    }
+
+    pub fn key_event(mut self, key_event:bool) -> BlockBuilder {
+        self.block.attributes.keyEvent = Some(key_event);
+        self
+    }
+
+    pub fn title(mut self, title:Option<&str>) -> BlockBuilder {
+        self.block.attributes.title = title.map(|t| t.to_owned());
+        self
+    }
+
+    pub fn published(mut self, date:Option<&str>) -> BlockBuilder {
+        self.block.firstPublishedDate = date.map(|d| d.to_owned());
+        self
+    }
+
+    pub fn build(self) -> CapiBlock {
+        self.block
+    }
+
+    /// The block as a liveblog's main block, with no elements
+    pub fn main(self) -> CapiMainBlock {
+        CapiMainBlock { block: self.block, elements: vec!() }
+    }
+}
+
+/// Builds the `Stats` of a liveblog. They start out as one summary among three blocks and no key events, published
+/// at 2022-01-02T03:04:05Z and retrieved a day later, with no tags or pinned blocks.
+pub struct StatsBuilder<'a> {
+    stats: Stats<'a>,
+}
+
+pub fn stats(id:&str) -> StatsBuilder<'_> {
+    StatsBuilder {
+        stats: Stats {
+            original_id: id,
+            web_publication_date: DateTime::parse_from_rfc3339("2022-01-02T03:04:05Z").unwrap(),
+            retrieved_at: DateTime::parse_from_rfc3339("2022-01-03T03:04:05Z").unwrap(),
+            summary_block_count: 1,
+            total_block_count: 3,
+            key_event_block_count: 0,
+            keyword_tags: vec!(),
+            pinned_block_ids: vec!(),
+        },
+    }
+}
+
+impl<'a> StatsBuilder<'a> {
+    pub fn published(mut self, date:&str) -> StatsBuilder<'a> {
+        self.stats.web_publication_date = DateTime::parse_from_rfc3339(date).unwrap();
+        self
+    }
+
+    pub fn block_counts(mut self, summary:usize, total:usize, key_event:usize) -> StatsBuilder<'a> {
+        self.stats.summary_block_count = summary;
+        self.stats.total_block_count = total;
+        self.stats.key_event_block_count = key_event;
+        self
+    }
+
+    pub fn tags(mut self, tags:Vec<CapiTag>) -> StatsBuilder<'a> {
+        self.stats.keyword_tags = tags;
+        self
+    }
+
+    pub fn build(self) -> Stats<'a> {
+        self.stats
+    }
+}
+
+/// A keyword tag whose title is "Tag {id}"
+pub fn keyword_tag(id:&str) -> CapiTag {
+    CapiTag { id: id.to_owned(), webTitle: format!("Tag {}", id), r#type: "keyword".to_owned() }
+}
@@ -0,0 +1,565 @@
+/// Elements that are dropped along with everything inside them. Guardian blocks use `figure` and `aside` for embedded
+/// media, tweets and rich links, which have no useful text of their own.
+const DROPPED_ELEMENTS:[&str; 11] = ["script", "style", "figure", "aside", "iframe", "noscript", "object", "template", "svg", "video", "audio"];
+
+/// Elements whose content is not HTML, so it must be skipped without looking for tags inside it
+const RAW_TEXT_ELEMENTS:[&str; 2] = ["script", "style"];
+
+/// Elements that start and end a paragraph
+const PARAGRAPH_ELEMENTS:[&str; 15] = ["p", "div", "h1", "h2", "h3", "h4", "h5", "h6", "section", "article", "header", "footer", "table", "pre", "hr"];
+
+/// Elements that start and end a line
+const LINE_ELEMENTS:[&str; 4] = ["li", "tr", "dt", "dd"];
+
+/// Characters that are escaped in Markdown mode so that they are not taken as formatting
+const MARKDOWN_SPECIAL:[char; 6] = ['\\', '*', '_', '[', ']', '`'];
+
+/// Attributes that embeds keep their URL in, in the order they are looked for
+const EMBED_URL_ATTRIBUTES:[&str; 3] = ["data-canonical-url", "href", "src"];
+
+/// Builds up the text, collapsing whitespace and only writing line breaks and prefixes once there is text to follow them.
+/// In Markdown mode links, emphasis and embeds are written as Markdown too, and characters that Markdown would
+/// otherwise treat as formatting are escaped.
+struct TextBuilder {
+    markdown: bool,
+    out: String,
+    /// Number of newlines to write before the next text: 1 for a new line, 2 for a new paragraph
+    breaks: usize,
+    line_started: bool,
+    pending_space: bool,
+    quote_depth: usize,
+    /// For each list we are inside, whether it is numbered and how many items it has had
+    lists: Vec<(bool, usize)>,
+    item_prefix: Option<String>,
+    /// The target of each link we are inside, if it had one
+    links: Vec<Option<String>>,
+}
+
+impl TextBuilder {
+    fn new(markdown:bool) -> TextBuilder {
+        TextBuilder { markdown, out: String::new(), breaks: 0, line_started: false, pending_space: false, quote_depth: 0, lists: vec!(), item_prefix: None, links: vec!() }
+    }
+
+    fn break_line(&mut self, breaks:usize) {
+        if self.line_started {
+            self.breaks = self.breaks.max(breaks);
+            self.line_started = false;
+        } else if !self.out.is_empty() {
+            self.breaks = self.breaks.max(breaks);
+        }
+        self.pending_space = false;
+    }
+
+    fn start_line(&mut self) {
+        if !self.out.is_empty() {
+            for _ in 0..self.breaks.max(1) {
+                self.out.push('\n');
+            }
+        }
+        for _ in 0..self.quote_depth {
+            self.out.push_str("> ");
+        }
+        if let Some(prefix) = self.item_prefix.take() {
+            self.out.push_str(&prefix);
+        }
+        self.breaks = 0;
+        self.line_started = true;
+    }
+
+    fn push_text(&mut self, text:&str) {
+        for c in text.chars() {
+            if c.is_whitespace() {
+                self.pending_space = self.line_started;
+                continue;
+            }
+            if !self.line_started {
+                self.start_line();
+            } else if self.pending_space {
+                self.out.push(' ');
+            }
+            self.pending_space = false;
+            if self.markdown && MARKDOWN_SPECIAL.contains(&c) {
+                self.out.push('\\');
+            }
+            self.out.push(c);
+        }
+    }
+
+    /// Writes Markdown formatting. Anything that opens, like `[` or `**`, goes after a pending space and anything that
+    /// closes goes before it, so that the formatting hugs the text inside it.
+    fn push_markup(&mut self, markup:&str, opening:bool) {
+        if !self.line_started {
+            if !opening {
+                return;
+            }
+            self.start_line();
+        } else if opening && self.pending_space {
+            self.out.push(' ');
+            self.pending_space = false;
+        }
+        self.out.push_str(markup);
+    }
+
+    /// Writes an embed as a paragraph holding a link to it
+    fn push_embed(&mut self, label:&str, url:&str) {
+        self.break_line(2);
+        self.push_markup(&format!("[{}]({})", label, url), true);
+        self.break_line(2);
+    }
+
+    /// In Markdown mode, links to an element that was dropped if we found out where it came from
+    fn push_dropped(&mut self, dropped:&DroppedElement) {
+        if let (true, Some(url)) = (self.markdown, &dropped.url) {
+            self.push_embed(&dropped.label, url);
+        }
+    }
+
+    fn start_item(&mut self) {
+        self.break_line(1);
+        let depth = self.lists.len().saturating_sub(1);
+        let marker = match self.lists.last_mut() {
+            Some((true, count))=>{
+                *count += 1;
+                format!("{}. ", count)
+            },
+            _=>"- ".to_owned(),
+        };
+        self.item_prefix = Some(format!("{}{}", "  ".repeat(depth), marker));
+    }
+
+    fn open(&mut self, name:&str, tag:&str) {
+        if self.markdown {
+            match name {
+                "a"=>{
+                    let href = tag_attribute(tag, "href");
+                    if href.is_some() {
+                        self.push_markup("[", true);
+                    }
+                    self.links.push(href);
+                    return;
+                },
+                "b" | "strong"=>return self.push_markup("**", true),
+                "i" | "em"=>return self.push_markup("*", true),
+                "img"=>{
+                    if let Some(src) = tag_attribute(tag, "src") {
+                        let alt = tag_attribute(tag, "alt").filter(|a| !a.trim().is_empty());
+                        let label = alt.map(|a| format!("Image: {}", a.trim())).unwrap_or_else(|| "Image".to_owned());
+                        self.push_embed(&label, &src);
+                    }
+                    return;
+                },
+                _=>(),
+            }
+        }
+        match name {
+            "br"=>self.break_line(1),
+            "li"=>self.start_item(),
+            "blockquote"=>{
+                self.break_line(2);
+                self.quote_depth += 1;
+            },
+            //a list inside another list carries straight on from the item it is in
+            "ul" | "ol"=>{
+                self.break_line(if self.lists.is_empty() { 2 } else { 1 });
+                self.lists.push((name=="ol", 0));
+            },
+            "td" | "th"=>self.pending_space = self.line_started,
+            _ if PARAGRAPH_ELEMENTS.contains(&name)=>self.break_line(2),
+            _ if LINE_ELEMENTS.contains(&name)=>self.break_line(1),
+            _=>(),
+        }
+    }
+
+    fn close(&mut self, name:&str) {
+        if self.markdown {
+            match name {
+                "a"=>{
+                    if let Some(Some(href)) = self.links.pop() {
+                        self.push_markup(&format!("]({})", href), false);
+                    }
+                    return;
+                },
+                "b" | "strong"=>return self.push_markup("**", false),
+                "i" | "em"=>return self.push_markup("*", false),
+                _=>(),
+            }
+        }
+        match name {
+            "blockquote"=>{
+                self.break_line(2);
+                self.quote_depth = self.quote_depth.saturating_sub(1);
+            },
+            "ul" | "ol"=>{
+                self.lists.pop();
+                self.break_line(if self.lists.is_empty() { 2 } else { 1 });
+            },
+            "li"=>{
+                self.break_line(1);
+                self.item_prefix = None;
+            },
+            _ if PARAGRAPH_ELEMENTS.contains(&name)=>self.break_line(2),
+            _ if LINE_ELEMENTS.contains(&name)=>self.break_line(1),
+            _=>(),
+        }
+    }
+}
+
+/// Returns the character for a named entity, for the ones that turn up in liveblogs
+fn named_entity(name:&str) -> Option<char> {
+    let c = match name {
+        "amp"=>'&',
+        "lt"=>'<',
+        "gt"=>'>',
+        "quot"=>'"',
+        "apos"=>'\'',
+        "nbsp"=>' ',
+        "ndash"=>'–',
+        "mdash"=>'—',
+        "hellip"=>'…',
+        "lsquo"=>'‘',
+        "rsquo"=>'’',
+        "ldquo"=>'“',
+        "rdquo"=>'”',
+        "pound"=>'£',
+        "euro"=>'€',
+        "dollar"=>'$',
+        "copy"=>'©',
+        "reg"=>'®',
+        "trade"=>'™',
+        "deg"=>'°',
+        "middot"=>'·',
+        "bull"=>'•',
+        "times"=>'×',
+        "frac12"=>'½',
+        "eacute"=>'é',
+        _=>return None,
+    };
+    Some(c)
+}
+
+/// Decodes a single entity, given the text between `&` and `;`
+fn decode_entity(entity:&str) -> Option<char> {
+    if let Some(number) = entity.strip_prefix('#') {
+        let code = match number.strip_prefix('x').or_else(|| number.strip_prefix('X')) {
+            Some(hex)=>u32::from_str_radix(hex, 16).ok()?,
+            None=>number.parse::<u32>().ok()?,
+        };
+        char::from_u32(code)
+    } else {
+        named_entity(entity)
+    }
+}
+
+/// Replaces character references like `&amp;`, `&#8217;` and `&#x2019;` with the characters they stand for.
+/// Anything that is not a reference we know is left as it is.
+pub fn decode_entities(text:&str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let after = &rest[amp + 1..];
+        let decoded = after.find(';')
+            .filter(|semi| *semi <= 10)
+            .and_then(|semi| decode_entity(&after[..semi]).map(|c| (c, semi)));
+        match decoded {
+            Some((c, semi))=>{
+                out.push(c);
+                rest = &after[semi + 1..];
+            },
+            None=>{
+                out.push('&');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Finds the `>` that ends a tag starting at `start`, skipping over any in quoted attribute values
+fn find_tag_end(html:&str, start:usize) -> Option<usize> {
+    let mut quote:Option<char> = None;
+    for (idx, c) in html[start..].char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c==q=>quote = None,
+            (Some(_), _)=>(),
+            (None, '"') | (None, '\'')=>quote = Some(c),
+            (None, '>')=>return Some(start + idx),
+            _=>(),
+        }
+    }
+    None
+}
+
+/// Returns the value of an attribute, given the source of a tag without its `<` and `>`, with entities decoded
+fn tag_attribute(tag:&str, name:&str) -> Option<String> {
+    let mut rest = tag.trim_start_matches('/').trim_start_matches(|c:char| c.is_ascii_alphanumeric());
+    loop {
+        rest = rest.trim_start_matches(|c:char| c.is_whitespace() || c=='/');
+        if rest.is_empty() {
+            return None;
+        }
+        let name_end = rest.find(|c:char| c.is_whitespace() || c=='=' || c=='/').unwrap_or(rest.len());
+        let attr_name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+
+        let mut value = None;
+        if let Some(after_equals) = rest.strip_prefix('=') {
+            let after_equals = after_equals.trim_start();
+            let (v, remainder) = match after_equals.chars().next() {
+                Some(q) if q=='"' || q=='\''=>{
+                    let end = after_equals[1..].find(q).map(|e| e + 1).unwrap_or(after_equals.len());
+                    (&after_equals[1..end], after_equals.get(end + 1..).unwrap_or(""))
+                },
+                _=>{
+                    let end = after_equals.find(char::is_whitespace).unwrap_or(after_equals.len());
+                    (&after_equals[..end], &after_equals[end..])
+                }
+            };
+            value = Some(v);
+            rest = remainder;
+        }
+        if attr_name.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(value.unwrap_or("")));
+        }
+    }
+}
+
+/// Finds the URL of an embed in the attributes of one of its tags
+fn embed_url(tag:&str) -> Option<String> {
+    EMBED_URL_ATTRIBUTES.iter().find_map(|a| tag_attribute(tag, a)).filter(|u| u.starts_with("http"))
+}
+
+/// Describes an embed for its Markdown link, using the Guardian's `element-*` class if it has one
+fn embed_label(tag:&str) -> String {
+    let kind = tag_attribute(tag, "class")
+        .and_then(|class| class.split_whitespace().find_map(|c| c.strip_prefix("element-").map(|k| k.replace('-', " "))));
+    match kind {
+        Some(k)=>format!("Embedded {}", k),
+        None=>"Embedded content".to_owned(),
+    }
+}
+
+/// An element that is being dropped, and what we know about it so far
+struct DroppedElement {
+    name: String,
+    /// How deeply it is nested inside itself
+    depth: usize,
+    label: String,
+    url: Option<String>,
+}
+
+/// Converts the HTML of a block into plain text.
+/// Paragraphs and headings are separated by blank lines, list items are put on their own lines starting with `- ` or
+/// their number, and blockquotes are prefixed with `> `. Entities are decoded and runs of whitespace collapsed.
+/// Scripts, styles and embedded media, tweets and rich links are dropped.
+/// This is not a full HTML parser, but it copes with the markup that CAPI produces and does not fail on broken markup.
+pub fn html_to_text(html:&str) -> String {
+    convert(html, false)
+}
+
+/// Converts the HTML of a block into Markdown. This is laid out like `html_to_text`, but keeps links and emphasis, and
+/// embedded media, tweets and rich links become links to the original where one can be found.
+pub fn html_to_markdown(html:&str) -> String {
+    convert(html, true)
+}
+
+/// Lower-cases the text and replaces punctuation with spaces, keeping apostrophes, so phrases can be matched on whole words
+pub fn normalise_words(text:&str) -> String {
+    let cleaned:String = text.chars()
+        .map(|c| match c {
+            '\u{2018}' | '\u{2019}'=>'\'',
+            c if c.is_alphanumeric() || c=='\''=>c,
+            _=>' ',
+        })
+        .flat_map(char::to_lowercase)
+        .collect();
+    cleaned.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Whether the HTML is nothing but embedded media, tweets, rich links or images, with no text of its own
+pub fn is_embed_only(html:&str) -> bool {
+    if !html_to_text(html).is_empty() {
+        return false;
+    }
+    let lower = html.to_ascii_lowercase();
+    DROPPED_ELEMENTS.iter()
+        .filter(|e| !RAW_TEXT_ELEMENTS.contains(e))
+        .chain(["img"].iter())
+        .any(|e| has_tag(&lower, e))
+}
+
+/// Whether there is an opening tag for the element in some lower-cased HTML
+fn has_tag(lower:&str, name:&str) -> bool {
+    let opening = format!("<{}", name);
+    lower.match_indices(&opening).any(|(idx, _)| {
+        match lower[idx + opening.len()..].chars().next() {
+            Some(c)=>c.is_whitespace() || c=='>' || c=='/',
+            None=>false,
+        }
+    })
+}
+
+fn convert(html:&str, markdown:bool) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut builder = TextBuilder::new(markdown);
+    let mut dropping:Option<DroppedElement> = None;
+    let mut pos = 0;
+
+    while pos < html.len() {
+        let lt = match html[pos..].find('<') {
+            Some(offset)=>pos + offset,
+            None=>html.len(),
+        };
+        if dropping.is_none() && lt > pos {
+            builder.push_text(&decode_entities(&html[pos..lt]));
+        }
+        if lt>=html.len() {
+            break;
+        }
+
+        if lower[lt..].starts_with("<!--") {
+            pos = lower[lt..].find("-->").map(|end| lt + end + 3).unwrap_or(html.len());
+            continue;
+        }
+
+        let tag_start = lt + 1;
+        let closing = html[tag_start..].starts_with('/');
+        let name_start = if closing { tag_start + 1 } else { tag_start };
+        let name:String = lower[name_start..].chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+        let is_declaration = html[tag_start..].starts_with('!') || html[tag_start..].starts_with('?');
+        if name.is_empty() && !is_declaration {
+            //a `<` that does not start a tag, like "a < b"
+            if dropping.is_none() {
+                builder.push_text("<");
+            }
+            pos = tag_start;
+            continue;
+        }
+        let tag_end = match find_tag_end(html, tag_start) {
+            Some(end)=>end,
+            None=>break,
+        };
+        pos = tag_end + 1;
+        if is_declaration {
+            continue;
+        }
+        let tag = &html[tag_start..tag_end];
+        let self_closing = tag.ends_with('/');
+
+        if let Some(dropped) = dropping.as_mut() {
+            if dropped.url.is_none() && !closing {
+                dropped.url = embed_url(tag);
+            }
+            if dropped.name==name {
+                if closing {
+                    dropped.depth -= 1;
+                } else if !self_closing {
+                    dropped.depth += 1;
+                }
+            }
+            if dropped.depth==0 {
+                builder.push_dropped(dropped);
+                dropping = None;
+            }
+            continue;
+        }
+
+        if closing {
+            builder.close(&name);
+        } else if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            let end_tag = format!("</{}", name);
+            pos = match lower[pos..].find(&end_tag) {
+                Some(offset)=>find_tag_end(html, pos + offset).map(|end| end + 1).unwrap_or(html.len()),
+                None=>html.len(),
+            };
+        } else if DROPPED_ELEMENTS.contains(&name.as_str()) {
+            let dropped = DroppedElement { name, depth: if self_closing { 0 } else { 1 }, label: embed_label(tag), url: embed_url(tag) };
+            if dropped.depth==0 {
+                builder.push_dropped(&dropped);
+            } else {
+                dropping = Some(dropped);
+            }
+        } else {
+            builder.open(&name, tag);
+            if self_closing {
+                builder.close(&name);
+            }
+        }
+    }
+
+    builder.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_paragraphs() {
+        assert_eq!(html_to_text("<p>Hello <b>there</b>,\n  world</p>\n<p>Second   paragraph<br>on two lines</p>"), "Hello there, world\n\nSecond paragraph\non two lines");
+        assert_eq!(html_to_text("<h2>Heading</h2><p>Text</p>"), "Heading\n\nText");
+        assert_eq!(html_to_text("  plain text  "), "plain text");
+        assert_eq!(html_to_text(""), "");
+    }
+
+    #[test]
+    pub fn test_lists_and_quotes() {
+        assert_eq!(html_to_text("<p>Shopping:</p><ul><li>eggs</li><li>milk <i>and</i> bread</li></ul><p>Done</p>"), "Shopping:\n\n- eggs\n- milk and bread\n\nDone");
+        assert_eq!(html_to_text("<ol><li>one</li><li>two<ul><li>nested</li></ul></li><li>three</li></ol>"), "1. one\n2. two\n  - nested\n3. three");
+        assert_eq!(html_to_text("<p>He said:</p><blockquote><p>First</p><p>Second</p></blockquote><p>After</p>"), "He said:\n\n> First\n\n> Second\n\nAfter");
+    }
+
+    #[test]
+    pub fn test_entities() {
+        assert_eq!(decode_entities("Fish &amp; chips &#8211; &#x2019;&pound;5&rsquo; &unknown; AT&T"), "Fish & chips – ’£5’ &unknown; AT&T");
+        assert_eq!(html_to_text("<p>a&nbsp;&nbsp;b &lt;c&gt;</p>"), "a b <c>");
+    }
+
+    #[test]
+    pub fn test_drops_scripts_and_embeds() {
+        let html = r#"<p>Before</p><script>if (a<b) { document.write("</p>"); }</script><figure class="element element-tweet"><blockquote class="twitter-tweet"><p>A tweet</p></blockquote><figure>nested</figure></figure><style>p > b { color: red }</style><!-- comment <p>hidden</p> --><p>After <img src="x.jpg" alt="pic"/></p>"#;
+        assert_eq!(html_to_text(html), "Before\n\nAfter");
+    }
+
+    #[test]
+    pub fn test_is_embed_only() {
+        assert!(is_embed_only(r#"<figure class="element element-tweet"><blockquote><p>A tweet</p></blockquote></figure>"#));
+        assert!(is_embed_only(r#"<p><img src="x.jpg" alt="pic"/></p>"#));
+        assert!(!is_embed_only(r#"<p>Caption</p><figure class="element-image"></figure>"#));
+        assert!(!is_embed_only("<p></p>"));
+        assert!(!is_embed_only("<p><imgur></imgur></p>"));
+    }
+
+    #[test]
+    pub fn test_broken_markup() {
+        assert_eq!(html_to_text("<p>a < b and c > d"), "a < b and c > d");
+        assert_eq!(html_to_text("<p>unfinished <a href=\"x"), "unfinished");
+        assert_eq!(html_to_text("<p title='1 > 0'>quoted</p>"), "quoted");
+    }
+
+    #[test]
+    pub fn test_markdown() {
+        assert_eq!(html_to_markdown("<p>Read <a href=\"https://example.com/a?b=1&amp;c=2\">the <strong>full</strong> story</a>, <em>now</em>.</p>"), "Read [the **full** story](https://example.com/a?b=1&c=2), *now*.");
+        assert_eq!(html_to_markdown("<ul><li>one_two</li><li><b>bold </b>text</li></ul><blockquote><p>quote [1]</p></blockquote>"), "- one\\_two\n- **bold** text\n\n> quote \\[1\\]");
+        assert_eq!(html_to_markdown("<p><a name=\"anchor\">no link</a></p>"), "no link");
+    }
+
+    #[test]
+    pub fn test_markdown_embeds() {
+        let html = r#"<p>Before</p><figure class="element element-tweet" data-canonical-url="https://twitter.com/someone/status/1"><blockquote class="twitter-tweet"><p>A tweet</p></blockquote></figure><figure class="element element-image"><img src="https://i.guim.co.uk/pic.jpg" alt="A picture"/><figcaption>Caption</figcaption></figure><figure class="element element-embed"></figure><script>ignored()</script><p>After</p>"#;
+        assert_eq!(html_to_markdown(html), "Before\n\n[Embedded tweet](https://twitter.com/someone/status/1)\n\n[Embedded image](https://i.guim.co.uk/pic.jpg)\n\nAfter");
+        assert_eq!(html_to_markdown("<p>A <img src='https://i.guim.co.uk/pic.jpg' alt='cat'> picture</p>"), "A\n\n[Image: cat](https://i.guim.co.uk/pic.jpg)\n\npicture");
+        //embeds are still dropped from plain text
+        assert_eq!(html_to_text(html), "Before\n\nAfter");
+    }
+
+    #[test]
+    pub fn test_tag_attribute() {
+        assert_eq!(tag_attribute("a href=\"x\" class='y z' data-x=unquoted disabled", "class").as_deref(), Some("y z"));
+        assert_eq!(tag_attribute("a href=\"x\" class='y z' data-x=unquoted disabled", "data-x").as_deref(), Some("unquoted"));
+        assert_eq!(tag_attribute("a href=\"x\" class='y z' data-x=unquoted disabled", "disabled").as_deref(), Some(""));
+        assert_eq!(tag_attribute("img src = \"a.jpg\" /", "src").as_deref(), Some("a.jpg"));
+        assert_eq!(tag_attribute("a href=\"x\"", "title"), None);
+    }
+}
@@ -0,0 +1,223 @@
+use chrono::{DateTime, FixedOffset};
+use clap::ValueEnum;
+use serde::Serialize;
+use crate::models::*;
+use crate::markdown::format_timestamp;
+use crate::lengths::SegmentLengths;
+
+/// The order that events are put into the input of a training pair
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EventOrder {
+    /// Earliest first, which is how the story unfolded
+    Chronological,
+    /// Latest first, which is how a liveblog page reads
+    ReverseChronological,
+    /// The order the chopper put them in
+    AsChopped,
+}
+
+/// How the events of a segment are turned into the input text of a training pair
+#[derive(Clone, Debug)]
+pub struct PairOptions {
+    pub event_order: EventOrder,
+    /// Goes between the events in the input
+    pub separator: String,
+    pub include_titles: bool,
+    pub include_timestamps: bool,
+}
+
+impl Default for PairOptions {
+    fn default() -> PairOptions {
+        PairOptions { event_order: EventOrder::Chronological, separator: "\n\n".to_owned(), include_titles: false, include_timestamps: false }
+    }
+}
+
+/// Details of where a training pair came from, so it can be traced back or filtered
+#[derive(Debug, Serialize)]
+pub struct PairMeta<'a> {
+    pub liveblog_id: &'a str,
+    pub segment_index: usize,
+    pub web_publication_date: DateTime<FixedOffset>,
+    pub summary_block_id: &'a str,
+    pub summary_title: Option<&'a str>,
+    pub event_block_ids: Vec<&'a str>,
+    pub first_published: Option<DateTime<FixedOffset>>,
+    pub last_published: Option<DateTime<FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lengths: Option<SegmentLengths>,
+    /// Blocks of the segment flagged as near-duplicates of blocks earlier in the harvest
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub duplicate_block_ids: Vec<&'a str>,
+}
+
+/// One record of a summarisation dataset: the text of a segment's events as the input, and the text of its summary
+/// as the target
+#[derive(Debug, Serialize)]
+pub struct TrainingPair<'a> {
+    pub input: String,
+    pub target: String,
+    pub meta: PairMeta<'a>,
+}
+
+/// The text of one event, with its timestamp and title on a line before it if they were asked for
+fn event_text(block:&CapiBlock, options:&PairOptions) -> String {
+    let mut header:Vec<String> = vec!();
+    if options.include_timestamps {
+        if let Some(t) = block.first_published() {
+            header.push(format!("[{}]", format_timestamp(&t)));
+        }
+    }
+    if options.include_titles {
+        if let Some(title) = block.attributes.title.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+            header.push(title.to_owned());
+        }
+    }
+
+    let text = block.body_text();
+    if header.is_empty() {
+        text
+    } else {
+        format!("{}\n{}", header.join(" "), text)
+    }
+}
+
+/// The events of a segment in the order they go into a training pair
+fn ordered_events<'a>(segment:&SummarisedContent<'a>, order:EventOrder) -> Vec<&'a CapiBlock> {
+    match order {
+        EventOrder::Chronological=>segment.events_in_time_order(),
+        EventOrder::ReverseChronological=>{
+            let mut events = segment.events_in_time_order();
+            //undated events stay at the end
+            let dated = events.iter().take_while(|e| e.first_published().is_some()).count();
+            events[..dated].reverse();
+            events
+        },
+        EventOrder::AsChopped=>segment.events.clone(),
+    }
+}
+
+fn join_events(events:&[&CapiBlock], options:&PairOptions) -> String {
+    events.iter()
+        .map(|e| event_text(e, options))
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>()
+        .join(&options.separator)
+}
+
+/// The input text of the training pair for a segment, exactly as `TrainingPair::from_segment` builds it
+pub fn pair_input(segment:&SummarisedContent, options:&PairOptions) -> String {
+    join_events(&ordered_events(segment, options.event_order), options)
+}
+
+impl<'a> TrainingPair<'a> {
+    /// Builds the training pair for a segment. Returns None if the segment has no summary, or if either side would
+    /// be empty, as those are no use for training.
+    pub fn from_segment(liveblog_id:&'a str, web_publication_date:DateTime<FixedOffset>, index:usize, segment:&SummarisedContent<'a>, options:&PairOptions) -> Option<TrainingPair<'a>> {
+        let summary = segment.summary?;
+        let target = summary.body_text();
+
+        let events = ordered_events(segment, options.event_order);
+        let input = join_events(&events, options);
+
+        if input.is_empty() || target.is_empty() {
+            return None;
+        }
+
+        let time_range = segment.time_range();
+        Some(TrainingPair {
+            input,
+            target,
+            meta: PairMeta {
+                liveblog_id,
+                segment_index: index,
+                web_publication_date,
+                summary_block_id: &summary.id,
+                summary_title: summary.attributes.title.as_deref(),
+                event_block_ids: events.iter().map(|e| e.id.as_str()).collect(),
+                first_published: time_range.map(|(first, _)| first),
+                last_published: time_range.map(|(_, last)| last),
+                lengths: segment.lengths,
+                duplicate_block_ids: segment.duplicate_blocks.clone(),
+            },
+        })
+    }
+}
+
+/// Turns the backslash escapes `\n`, `\t` and `\\` into the characters they stand for, so that a separator with
+/// newlines in it can be given on the commandline
+pub fn unescape_separator(separator:&str) -> String {
+    let mut out = String::with_capacity(separator.len());
+    let mut chars = separator.chars();
+    while let Some(c) = chars.next() {
+        if c!='\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n')=>out.push('\n'),
+            Some('t')=>out.push('\t'),
+            Some('\\')=>out.push('\\'),
+            Some(other)=>{
+                out.push('\\');
+                out.push(other);
+            },
+            None=>out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::block;
+
+    fn publication_date() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2022-01-02T00:00:00Z").unwrap()
+    }
+
+    #[test]
+    pub fn test_pair_from_segment() {
+        let summary = block("summary").title(Some("Key points")).published(Some("2022-01-02T06:00:00Z")).build();
+        let events = [
+            block("b").title(Some("Second")).published(Some("2022-01-02T05:00:00Z")).build(),
+            block("undated").published(None).build(),
+            block("a").published(Some("2022-01-02T04:00:00Z")).build(),
+        ];
+        let segment = SummarisedContent::new(&summary, events.iter().collect());
+
+        let pair = TrainingPair::from_segment("news/live/blog", publication_date(), 3, &segment, &PairOptions::default()).unwrap();
+        assert_eq!(pair.input, "This is block a\n\nThis is block b\n\nThis is block undated");
+        assert_eq!(pair.target, "This is block summary");
+        assert_eq!(pair.meta.liveblog_id, "news/live/blog");
+        assert_eq!(pair.meta.segment_index, 3);
+        assert_eq!(pair.meta.summary_title, Some("Key points"));
+        assert_eq!(pair.meta.event_block_ids, vec!("a", "b", "undated"));
+        assert_eq!(pair.meta.last_published.unwrap().to_rfc3339(), "2022-01-02T06:00:00+00:00");
+
+        let options = PairOptions { event_order: EventOrder::ReverseChronological, separator: " | ".to_owned(), include_titles: true, include_timestamps: true };
+        let pair = TrainingPair::from_segment("news/live/blog", publication_date(), 3, &segment, &options).unwrap();
+        assert_eq!(pair.input, "[2022-01-02 05:00 UTC] Second\nThis is block b | [2022-01-02 04:00 UTC]\nThis is block a | This is block undated");
+
+        let options = PairOptions { event_order: EventOrder::AsChopped, ..PairOptions::default() };
+        let pair = TrainingPair::from_segment("news/live/blog", publication_date(), 3, &segment, &options).unwrap();
+        assert_eq!(pair.meta.event_block_ids, vec!("b", "undated", "a"));
+    }
+
+    #[test]
+    pub fn test_no_pair_without_summary_or_events() {
+        let summary = block("summary").published(None).build();
+        let events = [block("a").published(None).build()];
+        let no_summary = SummarisedContent { summary: None, events: events.iter().collect(), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() };
+        assert!(TrainingPair::from_segment("news/live/blog", publication_date(), 0, &no_summary, &PairOptions::default()).is_none());
+
+        let no_events = SummarisedContent::new(&summary, vec!());
+        assert!(TrainingPair::from_segment("news/live/blog", publication_date(), 0, &no_events, &PairOptions::default()).is_none());
+    }
+
+    #[test]
+    pub fn test_unescape_separator() {
+        assert_eq!(unescape_separator("\\n\\n"), "\n\n");
+        assert_eq!(unescape_separator(" \\t|\\\\n \\x"), " \t|\\n \\x");
+    }
+}
@@ -1,64 +1,820 @@
-use std::str;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::{create_dir_all, File};
-use crate::{models::*, capi};
-
-fn dir_name_from_capi_id(capi_id:&str) -> &str {
-    let id_parts = str::split(capi_id, "/");
-    match id_parts.last() {
-        Some(dirname)=>
-        if dirname.len()>200 {
-            let (head, _) = dirname.split_at(200);
-            return head;
-        } else {
-            return dirname;
+use std::fmt::Display;
+use std::fs::create_dir_all;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::models::*;
+use crate::jsonl_writer::{JsonlSink, StreamSink};
+use crate::sqlite_writer::SqliteSink;
+use crate::parquet_writer::{ParquetCompression, ParquetSink};
+use crate::file_store::{make_file_store, ArchiveFormat, FileStore};
+use crate::s3::{S3Client, S3Config, S3Store};
+use crate::projection::{Projection, ProjectedStats, StatsField};
+use crate::markdown::render_segment;
+use crate::split::{SplitConfig, SplitSink};
+use crate::training::PairOptions;
+use crate::manifest::ManifestFile;
+
+/// Longest directory name component we will create, in bytes. Most filesystems allow 255.
+const MAX_COMPONENT_LENGTH:usize = 200;
+
+/// Returned when two different liveblogs in the same run would be written to the same directory
+#[derive(Debug)]
+pub struct NameCollisionError {
+    pub dir_name: String,
+    pub first_id: String,
+    pub second_id: String,
+}
+
+impl Display for NameCollisionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{} and {} would both be written to {}", self.first_id, self.second_id, self.dir_name))
+    }
+}
+
+impl Error for NameCollisionError {
+
+}
+
+/// Returns the first 8 hex digits of the SHA-256 of the given string
+fn short_hash(s:&str) -> String {
+    Sha256::digest(s.as_bytes()).iter().take(4).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the longest prefix of `s` that is at most `max_len` bytes and does not split a character
+fn truncate_on_char_boundary(s:&str, max_len:usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Makes one part of a CAPI id safe to use as a directory name. Anything other than letters, digits, `-`, `_` and `.`
+/// is replaced, as are the special names `.` and `..`. If the part had to be changed or truncated then a hash of the
+/// original is added to the end, so that different ids cannot end up with the same name.
+fn safe_path_component(component:&str) -> String {
+    let sanitised:String = component.chars()
+        .map(|c| if c.is_alphanumeric() || c=='-' || c=='_' || c=='.' { c } else { '_' })
+        .collect();
+    let needs_hash = sanitised!=component || sanitised=="." || sanitised==".." || sanitised.len() > MAX_COMPONENT_LENGTH;
+
+    if needs_hash {
+        let hash = short_hash(component);
+        let head = truncate_on_char_boundary(&sanitised, MAX_COMPONENT_LENGTH - hash.len() - 1).trim_matches('.');
+        format!("{}-{}", head, hash)
+    } else {
+        sanitised
+    }
+}
+
+/// Turns a CAPI id like `politics/live/2023/oct/13/some-slug` into a relative directory path that keeps the whole
+/// section/date hierarchy, so liveblogs with the same slug on different days or in different sections stay apart.
+fn dir_name_from_capi_id(capi_id:&str) -> String {
+    let components:Vec<String> = capi_id.split('/')
+        .filter(|c| !c.is_empty())
+        .map(safe_path_component)
+        .collect();
+
+    if components.is_empty() {
+        "UNKNOWN".to_owned()
+    } else {
+        components.join("/")
+    }
+}
+
+fn write_json_to_store<T:Serialize>(store:&mut dyn FileStore, path:&str, content:&T) -> Result<(), Box<dyn Error>> {
+    let buf = serde_json::to_vec(content)?;
+    store.write_entry(path, &buf)
+}
+
+/// Name of the empty file that marks a liveblog directory as completely written
+pub const COMPLETE_MARKER:&str = "COMPLETE";
+
+/// The names of the output formats that can be selected from the commandline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One directory per liveblog, with one JSON file per segment
+    Directory,
+    /// One JSON Lines record per segment, in size-limited shards
+    Jsonl,
+    /// Normalised tables in a SQLite database, `liveblogs.sqlite` in the output path
+    Sqlite,
+    /// One row per segment in a new `segments-NNNNN.parquet` in the output path
+    Parquet,
+    /// Segment and stats records as JSON Lines on stdout, for piping into another program. Logging goes to stderr.
+    Stdout,
+    /// Summarisation training pairs, the events of each segment against its summary, in size-limited `pairs-NNNNN.jsonl` shards
+    Pairs,
+}
+
+impl OutputFormat {
+    /// Whether this format writes anything under the output path. If not then there is no manifest or harvest index.
+    pub fn writes_files(&self) -> bool {
+        *self!=OutputFormat::Stdout
+    }
+
+    /// Whether this format writes the models out as JSON, so that its fields can be picked with a `Projection`.
+    /// SQLite and parquet have fixed schemas.
+    pub fn is_json(&self) -> bool {
+        matches!(self, OutputFormat::Directory | OutputFormat::Jsonl | OutputFormat::Stdout)
+    }
+
+    /// Whether writing a liveblog that an earlier run wrote replaces the earlier copy. JSON Lines, pairs, parquet and
+    /// archives only ever add to what is already there.
+    pub fn replaces_liveblogs(&self, archive:ArchiveFormat) -> bool {
+        match self {
+            OutputFormat::Directory=>archive==ArchiveFormat::None,
+            OutputFormat::Sqlite | OutputFormat::Stdout=>true,
+            OutputFormat::Jsonl | OutputFormat::Parquet | OutputFormat::Pairs=>false,
         }
-        None=>"UNKNOWN"
     }
 }
 
-fn write_block_to_file(file_name:&String, b:&SummarisedContent) -> Result<(), Box<dyn Error>> {
-    let file = File::create(file_name)?;
-    match serde_json::to_writer(file, b) {
-        Ok(_)=>Ok(()),
-        Err(e)=>Err(Box::new(e))
+/// An OutputSink receives the chopped liveblogs and writes them somewhere.
+/// For each liveblog `begin_liveblog` is called first, then `write_segment` for each segment in order, then `write_stats`
+/// and finally `finish_liveblog`. If any of those fail then `abort_liveblog` is called instead of `finish_liveblog`.
+/// `flush` is called once everything has been written.
+pub trait OutputSink {
+    fn begin_liveblog(&mut self, main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>>;
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>>;
+    fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>>;
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>>;
+    /// Throws away whatever has been written of the current liveblog, where the sink is able to
+    fn abort_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), Box<dyn Error>>;
+    /// The files on disk that this run has produced, for the dataset manifest
+    fn output_files(&self) -> Vec<PathBuf>;
+    /// Whether each liveblog is in its final place in the output once `finish_liveblog` returns. Sinks that only
+    /// complete their files in `flush` say no, so the harvest index waits for that before recording their liveblogs.
+    fn writes_through(&self) -> bool {
+        true
+    }
+    /// The objects this run has uploaded to S3, for the dataset manifest
+    fn uploaded_objects(&self) -> Vec<ManifestFile> {
+        vec!()
     }
 }
 
-fn write_summary_to_file(file_name:&String, s:&Stats) -> Result<(), Box<dyn Error>> {
-    let file = File::create(file_name)?;
-    match serde_json::to_writer(file, s) {
-        Ok(_)=>Ok(()),
-        Err(e)=>Err(Box::new(e))
-    } 
+/// The settings `make_sink` needs to choose and build the output sink
+pub struct SinkOptions {
+    pub output_format: OutputFormat,
+    pub projection: Projection,
+    pub markdown: bool,
+    pub archive: ArchiveFormat,
+    pub liveblogs_per_archive: usize,
+    pub gzip_files: bool,
+    /// Maximum size of each shard in bytes, for the jsonl and pairs output formats
+    pub shard_max_bytes: u64,
+    pub parquet_compression: ParquetCompression,
+    pub parquet_row_group_size: usize,
+    pub pair_options: PairOptions,
+    /// Where to upload the output instead of writing it under the output path
+    pub s3: Option<S3Config>,
+    /// How to split the output into train, validation and test sets
+    pub split: Option<SplitConfig>,
 }
 
-pub fn write_out_data(base_path:&str, capi_id:&str, chopped_blocks:&Vec<SummarisedContent>, stats:&Stats) -> Result<(), Box<dyn Error>> {
-    let dir_name = format!("{}/{}", base_path, dir_name_from_capi_id(capi_id));
+/// Builds the output sink selected on the commandline, writing under the given path or uploading to S3.
+/// `run_name` identifies this run, for outputs that need to keep their files apart from earlier runs'.
+/// If the output is being split into train, validation and test sets then each one gets its own sink, writing to a
+/// subdirectory of the output path or S3 prefix named after the split.
+pub fn make_sink(options:&SinkOptions, output_path:&str, run_name:&str) -> Result<Box<dyn OutputSink>, Box<dyn Error>> {
+    if !options.projection.is_everything() && !options.output_format.is_json() {
+        return Err("--block-fields and --stats-fields only apply to the directory, jsonl and stdout output formats".into());
+    }
+    if options.markdown && options.output_format!=OutputFormat::Directory {
+        return Err("--markdown only applies to the directory output format".into());
+    }
 
-    match create_dir_all(&dir_name) {
-        Ok(_)=> (),
-        Err(e) => println!("WARNING unable to create {}: {}", dir_name, e),
+    match &options.split {
+        Some(config)=>{
+            if !options.output_format.writes_files() {
+                return Err("the stdout output format cannot be split".into());
+            }
+            let sink = SplitSink::new(config.clone(), |split| make_format_sink(options, &format!("{}/{}", output_path, split.name()), run_name, Some(split.name())))?;
+            Ok(Box::new(sink))
+        },
+        None=>make_format_sink(options, output_path, run_name, None),
     }
+}
 
-    println!("DEBUG dirname is {}", dir_name);
+/// Builds the sink for the output format. `subdirectory` is added to the end of the S3 prefix, as it already has been
+/// to `output_path`.
+fn make_format_sink(options:&SinkOptions, output_path:&str, run_name:&str, subdirectory:Option<&str>) -> Result<Box<dyn OutputSink>, Box<dyn Error>> {
+    if let Some(config) = &options.s3 {
+        if options.archive!=ArchiveFormat::None || options.gzip_files {
+            return Err("--archive and --gzip-files cannot be used when uploading to S3".into());
+        }
+        let config = match subdirectory {
+            Some(subdirectory)=>config.clone().with_subdirectory(subdirectory),
+            None=>config.clone(),
+        };
+        let client = Arc::new(S3Client::new(config)?);
+        return match options.output_format {
+            OutputFormat::Directory=>Ok(Box::new(DirectorySink::new(Box::new(S3Store::new(client))).with_projection(options.projection.clone()).with_markdown(options.markdown))),
+            OutputFormat::Jsonl=>Ok(Box::new(JsonlSink::new_s3(client, "segments", options.shard_max_bytes, run_name).with_projection(options.projection.clone()))),
+            OutputFormat::Pairs=>Ok(Box::new(JsonlSink::new_s3(client, "pairs", options.shard_max_bytes, run_name).with_training_pairs(options.pair_options.clone()))),
+            _=>Err("only the directory, jsonl and pairs output formats can be uploaded to S3".into()),
+        };
+    }
 
-    //now write out all the summarised blocks we found
-    for block in chopped_blocks.iter() {
-        let id_to_use:String = block.summary.as_ref().map_or_else(|| "HEAD".to_owned(), |summ| summ.id.clone());
-        let file_name = format!("{}/{}.json",dir_name, id_to_use);
-        match write_block_to_file(&file_name, block) {
-            Ok(_)=>continue,
+    match options.output_format {
+        OutputFormat::Directory=>{
+            let store = make_file_store(output_path, options.archive, options.liveblogs_per_archive, options.gzip_files);
+            Ok(Box::new(DirectorySink::new(store).with_projection(options.projection.clone()).with_markdown(options.markdown)))
+        },
+        OutputFormat::Jsonl=>Ok(Box::new(JsonlSink::new(output_path, "segments", options.shard_max_bytes)?.with_projection(options.projection.clone()))),
+        OutputFormat::Pairs=>Ok(Box::new(JsonlSink::new(output_path, "pairs", options.shard_max_bytes)?.with_training_pairs(options.pair_options.clone()))),
+        OutputFormat::Sqlite=>{
+            create_dir_all(output_path)?;
+            Ok(Box::new(SqliteSink::new(&format!("{}/liveblogs.sqlite", output_path))?))
+        },
+        OutputFormat::Parquet=>Ok(Box::new(ParquetSink::new(output_path, options.parquet_compression, options.parquet_row_group_size)?)),
+        OutputFormat::Stdout=>Ok(Box::new(StreamSink::new(BufWriter::new(io::stdout())).with_projection(options.projection.clone()))),
+    }
+}
+
+/// Sends one chopped liveblog through the given sink.
+/// Any error stops the liveblog straight away and aborts it, so it is never marked as finished if part of it could
+/// not be written.
+pub fn write_liveblog(sink:&mut dyn OutputSink, chopped_blocks:&[SummarisedContent], main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+    let result = (|| {
+        sink.begin_liveblog(main, stats)?;
+
+        for (idx, block) in chopped_blocks.iter().enumerate() {
+            sink.write_segment(idx, block)
+                .map_err(|e| format!("could not write segment {} of {}: {}", idx, stats.original_id, e))?;
+        }
+
+        sink.write_stats(stats)?;
+        sink.finish_liveblog()
+    })();
+
+    if result.is_err() {
+        if let Err(e) = sink.abort_liveblog() {
+            eprintln!("WARNING could not abort {}: {}", stats.original_id, e);
+        }
+    }
+    result
+}
+
+/// Compact, filename-safe form of a block timestamp, e.g. `20220102T030405Z`
+fn file_timestamp(t:&DateTime<FixedOffset>) -> String {
+    t.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Returns the file name for a segment, `{index}_{first}-{last}_{id}.json`. The zero-padded index means a plain
+/// directory listing shows the segments in the order they were written. The id is the summary block's, or for segments
+/// without a summary the first block's, except for the leading one which is always HEAD.
+fn segment_file_name(index:usize, segment:&SummarisedContent) -> String {
+    let id_to_use:String = match (segment.summary, segment.events.first()) {
+        (Some(summ), _)=>safe_path_component(&summ.id),
+        (None, Some(first)) if index>0 =>safe_path_component(&first.id),
+        (None, _)=>"HEAD".to_owned(),
+    };
+    let time_range = match segment.time_range() {
+        Some((first, last))=>format!("{}-{}", file_timestamp(&first), file_timestamp(&last)),
+        None=>"undated".to_owned(),
+    };
+    format!("{:05}_{}_{}.json", index, time_range, id_to_use)
+}
+
+/// Describes one segment file in `META.json`
+#[derive(Debug, Serialize)]
+pub struct SegmentMeta {
+    pub index: usize,
+    pub file_name: String,
+    pub block_count: usize,
+    pub first_published: Option<DateTime<FixedOffset>>,
+    pub last_published: Option<DateTime<FixedOffset>>,
+    /// The Markdown rendering of the segment, if one was written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub markdown_file: Option<String>,
+}
+
+/// The content of `META.json`: the liveblog's stats, plus the list of its segments in order. `original_id` is written
+/// even if the stats fields were projected without it, so a later run can always tell whose directory it is.
+#[derive(Debug, Serialize)]
+struct DirectoryMeta<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_id: Option<&'a str>,
+    #[serde(flatten)]
+    stats: ProjectedStats<'a>,
+    segments: &'a [SegmentMeta],
+}
+
+/// The part of an earlier `META.json` needed to check whose it was and tidy up after it
+#[derive(Debug, Deserialize)]
+struct PreviousMeta {
+    #[serde(default)]
+    original_id: Option<String>,
+    #[serde(default)]
+    segments: Vec<PreviousSegment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PreviousSegment {
+    file_name: String,
+    #[serde(default)]
+    markdown_file: Option<String>,
+}
+
+/// Writes each liveblog to its own directory, whose path is made from its CAPI id by `dir_name_from_capi_id`.
+/// Each segment goes into a file named by `segment_file_name`, `MAIN.json` holds the main block and standfirst
+/// and `META.json` holds the stats and lists the segment files in order. The files themselves are handed to a `FileStore`, which decides whether they end up
+/// as loose files or in an archive.
+/// A liveblog's directory is only complete once its `COMPLETE` marker has been written, which happens after everything else.
+/// If two different liveblogs map to the same directory, either in one run or because an earlier run's `META.json`
+/// belongs to another liveblog, a `NameCollisionError` is returned rather than one overwriting the other.
+pub struct DirectorySink {
+    store: Box<dyn FileStore>,
+    dir_name: Option<String>,
+    written_dirs: HashMap<String, String>,
+    segments: Vec<SegmentMeta>,
+    projection: Projection,
+    markdown: bool,
+}
+
+impl DirectorySink {
+    pub fn new(store:Box<dyn FileStore>) -> DirectorySink {
+        DirectorySink { store, dir_name: None, written_dirs: HashMap::new(), segments: vec!(), projection: Projection::default(), markdown: false }
+    }
+
+    /// Writes only the fields picked by the given projection into the segment files and `META.json`.
+    /// `MAIN.json` and the segment list in `META.json` are always written in full.
+    pub fn with_projection(mut self, projection:Projection) -> DirectorySink {
+        self.projection = projection;
+        self
+    }
+
+    /// Reads the `META.json` an earlier run left in the given directory, if there is one that can be read
+    fn read_previous_meta(&self, dir_name:&str) -> Result<Option<PreviousMeta>, Box<dyn Error>> {
+        let meta_file = format!("{}/META.json", dir_name);
+        let previous = match self.store.read_entry(&meta_file)? {
+            Some(content)=>content,
+            None=>return Ok(None),
+        };
+        match serde_json::from_slice(&previous) {
+            Ok(m)=>Ok(Some(m)),
             Err(e)=>{
-                println!("ERROR Could not write to {}: {}", file_name, e);
-                break;
+                eprintln!("WARNING could not read the earlier {}, leaving its segments alone: {}", meta_file, e);
+                Ok(None)
             }
         }
     }
 
-    let file_name = format!("{}/META.json", dir_name);
+    /// Removes the segment files listed in an earlier `META.json` for the given directory, because a liveblog that is
+    /// written again may be cut up differently and leave files behind that no longer belong to it
+    fn remove_previous_segments(&mut self, dir_name:&str, meta:&PreviousMeta) -> Result<(), Box<dyn Error>> {
+        let previous_files = meta.segments.iter().flat_map(|s| std::iter::once(&s.file_name).chain(s.markdown_file.as_ref()));
+        for file_name in previous_files.filter(|f| !f.contains('/') && !f.starts_with('.')) {
+            self.store.remove_entry(&format!("{}/{}", dir_name, file_name))?;
+        }
+        Ok(())
+    }
+
+    /// Also writes each segment rendered as Markdown, in a `.md` file next to its JSON, for reviewing
+    pub fn with_markdown(mut self, markdown:bool) -> DirectorySink {
+        self.markdown = markdown;
+        self
+    }
+
+    fn current_dir(&self) -> Result<&String, Box<dyn Error>> {
+        self.dir_name.as_ref().ok_or_else(|| "write called before begin_liveblog".into())
+    }
+}
+
+impl OutputSink for DirectorySink {
+    fn begin_liveblog(&mut self, main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        let dir_name = dir_name_from_capi_id(stats.original_id);
+        eprintln!("DEBUG dirname is {}", dir_name);
+
+        match self.written_dirs.get(&dir_name) {
+            Some(first_id) if first_id!=stats.original_id=>{
+                return Err(Box::new(NameCollisionError {
+                    dir_name,
+                    first_id: first_id.to_owned(),
+                    second_id: stats.original_id.to_owned(),
+                }));
+            },
+            Some(_)=>(),
+            None=>{
+                self.written_dirs.insert(dir_name.to_owned(), stats.original_id.to_owned());
+            }
+        }
+
+        let previous = self.read_previous_meta(&dir_name)?;
+        if let Some(first_id) = previous.as_ref().and_then(|m| m.original_id.as_ref()).filter(|id| *id!=stats.original_id) {
+            return Err(Box::new(NameCollisionError {
+                dir_name,
+                first_id: first_id.to_owned(),
+                second_id: stats.original_id.to_owned(),
+            }));
+        }
+
+        //if this liveblog was harvested before, it is not complete again until we have finished re-writing it
+        self.store.remove_entry(&format!("{}/{}", dir_name, COMPLETE_MARKER))?;
+        if let Some(meta) = &previous {
+            self.remove_previous_segments(&dir_name, meta)?;
+        }
+
+        //the main block and standfirst are written once, rather than with every segment
+        let file_name = format!("{}/MAIN.json", dir_name);
+        self.dir_name = Some(dir_name);
+        self.segments.clear();
+        write_json_to_store(self.store.as_mut(), &file_name, main)
+    }
+
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+        let segment_file = segment_file_name(index, segment);
+        let file_name = format!("{}/{}", self.current_dir()?, segment_file);
+        write_json_to_store(self.store.as_mut(), &file_name, &self.projection.segment(segment)).map_err(|e| format!("could not write to {}: {}", file_name, e))?;
+
+        let markdown_file = if self.markdown {
+            let markdown_file = format!("{}.md", segment_file.strip_suffix(".json").unwrap_or(&segment_file));
+            let file_name = format!("{}/{}", self.current_dir()?, markdown_file);
+            self.store.write_entry(&file_name, render_segment(segment).as_bytes()).map_err(|e| format!("could not write to {}: {}", file_name, e))?;
+            Some(markdown_file)
+        } else {
+            None
+        };
+
+        let time_range = segment.time_range();
+        self.segments.push(SegmentMeta {
+            index,
+            file_name: segment_file,
+            block_count: segment.block_count(),
+            first_published: time_range.map(|(first, _)| first),
+            last_published: time_range.map(|(_, last)| last),
+            markdown_file,
+        });
+        Ok(())
+    }
 
-    //finally write out the metadata stats
-    write_summary_to_file(&file_name, stats)?;
-    Ok(())
-}
\ No newline at end of file
+    fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        let file_name = format!("{}/META.json", self.current_dir()?);
+        let meta = DirectoryMeta {
+            original_id: (!self.projection.writes_stats_field(StatsField::OriginalId)).then_some(stats.original_id),
+            stats: self.projection.stats(stats),
+            segments: &self.segments,
+        };
+        write_json_to_store(self.store.as_mut(), &file_name, &meta)
+    }
+
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        let marker = format!("{}/{}", self.current_dir()?, COMPLETE_MARKER);
+        self.store.write_entry(&marker, b"")?;
+        self.dir_name = None;
+        self.store.liveblog_finished()
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.close()
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        self.store.output_files()
+    }
+
+    fn writes_through(&self) -> bool {
+        self.store.writes_through()
+    }
+
+    fn uploaded_objects(&self) -> Vec<ManifestFile> {
+        self.store.uploaded_objects()
+    }
+}
+
+/// Keeps everything that is written in memory as JSON values, so that tests can check what a run produced
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySink {
+    pub liveblogs: Vec<MemoryLiveblog>,
+    pub flushed: bool,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryLiveblog {
+    pub capi_id: String,
+    pub main: serde_json::Value,
+    pub segments: Vec<(usize, serde_json::Value)>,
+    pub stats: Option<serde_json::Value>,
+    pub finished: bool,
+    pub aborted: bool,
+}
+
+#[cfg(test)]
+impl MemorySink {
+    fn current(&mut self) -> Result<&mut MemoryLiveblog, Box<dyn Error>> {
+        self.liveblogs.last_mut().ok_or_else(|| "write called before begin_liveblog".into())
+    }
+}
+
+#[cfg(test)]
+impl OutputSink for MemorySink {
+    fn begin_liveblog(&mut self, main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        self.liveblogs.push(MemoryLiveblog {
+            capi_id: stats.original_id.to_owned(),
+            main: serde_json::to_value(main)?,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+        let value = serde_json::to_value(segment)?;
+        self.current()?.segments.push((index, value));
+        Ok(())
+    }
+
+    fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>> {
+        let value = serde_json::to_value(stats)?;
+        self.current()?.stats = Some(value);
+        Ok(())
+    }
+
+    fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.current()?.finished = true;
+        Ok(())
+    }
+
+    fn abort_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+        self.current()?.aborted = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.flushed = true;
+        Ok(())
+    }
+
+    fn output_files(&self) -> Vec<PathBuf> {
+        vec!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{block, stats};
+    use crate::file_store::LooseFiles;
+    use std::fs::read_to_string;
+
+    #[test]
+    pub fn test_dir_name_keeps_hierarchy() {
+        assert_eq!(dir_name_from_capi_id("politics/live/2023/oct/13/some-slug"), "politics/live/2023/oct/13/some-slug");
+        assert_eq!(dir_name_from_capi_id("/world//live/2023/oct/13/some-slug/"), "world/live/2023/oct/13/some-slug");
+        assert_eq!(dir_name_from_capi_id(""), "UNKNOWN");
+    }
+
+    #[test]
+    pub fn test_dir_name_unsafe_components() {
+        let name = dir_name_from_capi_id("news/../secret/a b");
+        let parts:Vec<&str> = name.split('/').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "news");
+        assert_eq!(parts[1], format!("-{}", short_hash("..")));
+        assert_eq!(parts[3], format!("a_b-{}", short_hash("a b")));
+        //different originals that sanitise to the same thing still get different names
+        assert_ne!(dir_name_from_capi_id("news/a b"), dir_name_from_capi_id("news/a_b"));
+        assert_ne!(dir_name_from_capi_id("news/a b"), dir_name_from_capi_id("news/a?b"));
+    }
+
+    #[test]
+    pub fn test_dir_name_truncation() {
+        //300 two-byte characters, so cutting at an odd number of bytes would split one
+        let long_slug = "é".repeat(300);
+        let name = dir_name_from_capi_id(&format!("news/{}", long_slug));
+        let last = name.rsplit('/').next().unwrap();
+        assert!(last.len() <= MAX_COMPONENT_LENGTH);
+        assert!(last.ends_with(&format!("-{}", short_hash(&long_slug))));
+
+        let other = dir_name_from_capi_id(&format!("news/{}x", long_slug));
+        assert_ne!(name, other);
+    }
+
+    #[test]
+    pub fn test_directory_sink_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").summary(true).build()];
+        let main = block("main").main();
+        let segments = vec!(SummarisedContent::new(&blocks[0], vec!()));
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false)));
+
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+        //the same liveblog again is fine
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+        //but a different one that maps to the same place is not
+        let err = write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live//blog").build()).unwrap_err();
+        let collision = err.downcast_ref::<NameCollisionError>().unwrap();
+        assert_eq!(collision.dir_name, "news/live/blog");
+        assert_eq!(collision.first_id, "news/live/blog");
+        assert_eq!(collision.second_id, "news/live//blog");
+
+        //a later run finds the earlier liveblog's META.json in the way
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false)));
+        let err = write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live//blog").build()).unwrap_err();
+        assert_eq!(err.downcast_ref::<NameCollisionError>().unwrap().first_id, "news/live/blog");
+        assert!(dir.path().join("news/live/blog").join(COMPLETE_MARKER).exists());
+    }
+
+    #[test]
+    pub fn test_directory_sink_collision_with_projected_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").summary(true).build()];
+        let main = block("main").main();
+        let segments = vec!(SummarisedContent::new(&blocks[0], vec!()));
+        let projection = Projection::new(&[], &[StatsField::TotalBlockCount]);
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false))).with_projection(projection.clone());
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+
+        //the id is kept in META.json even though the stats fields leave it out
+        let meta:serde_json::Value = serde_json::from_str(&read_to_string(dir.path().join("news/live/blog/META.json")).unwrap()).unwrap();
+        assert_eq!(meta["original_id"], "news/live/blog");
+        assert_eq!(meta["total_block_count"], 3);
+
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false))).with_projection(projection);
+        let err = write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live//blog").build()).unwrap_err();
+        assert_eq!(err.downcast_ref::<NameCollisionError>().unwrap().first_id, "news/live/blog");
+    }
+
+    #[test]
+    pub fn test_write_liveblog_order() {
+        let blocks = [block("a").build(), block("b").summary(true).build(), block("c").build()];
+        let main = block("main").main();
+        let segments = vec!(
+            SummarisedContent { summary: None, events: vec!(&blocks[0]), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() },
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2])),
+        );
+        let mut sink = MemorySink::default();
+
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+        sink.flush().unwrap();
+
+        assert!(sink.flushed);
+        assert_eq!(sink.liveblogs.len(), 1);
+        let written = &sink.liveblogs[0];
+        assert_eq!(written.capi_id, "news/live/blog");
+        assert_eq!(written.main["main"]["id"], "main");
+        assert_eq!(written.segments.len(), 2);
+        assert_eq!(written.segments[1].0, 1);
+        assert_eq!(written.segments[1].1["summary"]["id"], "b");
+        assert_eq!(written.stats.as_ref().unwrap()["total_block_count"], 3);
+        assert!(written.finished);
+    }
+
+    #[test]
+    pub fn test_directory_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").build(), block("b").summary(true).build(), block("c").build()];
+        let main = block("main").main();
+        let segments = vec!(
+            SummarisedContent { summary: None, events: vec!(&blocks[0]), key_events: vec!(), context: None, lengths: None, duplicate_blocks: vec!() },
+            SummarisedContent::new(&blocks[1], vec!(&blocks[2])),
+        );
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false)));
+
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+
+        let liveblog_dir = dir.path().join("news/live/blog");
+        let head:serde_json::Value = serde_json::from_str(&read_to_string(liveblog_dir.join("00000_20220102T030405Z-20220102T030405Z_HEAD.json")).unwrap()).unwrap();
+        assert_eq!(head["events"][0]["id"], "a");
+        let summary:serde_json::Value = serde_json::from_str(&read_to_string(liveblog_dir.join("00001_20220102T030405Z-20220102T030405Z_b.json")).unwrap()).unwrap();
+        assert_eq!(summary["events"][0]["id"], "c");
+        assert!(liveblog_dir.join("MAIN.json").exists());
+
+        let meta:serde_json::Value = serde_json::from_str(&read_to_string(liveblog_dir.join("META.json")).unwrap()).unwrap();
+        assert_eq!(meta["original_id"], "news/live/blog");
+        assert_eq!(meta["segments"].as_array().unwrap().len(), 2);
+        assert_eq!(meta["segments"][0]["file_name"], "00000_20220102T030405Z-20220102T030405Z_HEAD.json");
+        assert_eq!(meta["segments"][1]["index"], 1);
+        assert_eq!(meta["segments"][1]["block_count"], 2);
+        assert_eq!(meta["segments"][1]["first_published"], "2022-01-02T03:04:05Z");
+        assert!(liveblog_dir.join(COMPLETE_MARKER).exists());
+    }
+
+    #[test]
+    pub fn test_segment_file_names() {
+        let mut blocks = [block("a").build(), block("b").summary(true).build(), block("c").build(), block("d").build()];
+        blocks[2].firstPublishedDate = Some("2022-01-02T05:06:07+01:00".to_owned());
+        blocks[3].firstPublishedDate = None;
+
+        let with_summary = SummarisedContent::new(&blocks[1], vec!(&blocks[2]));
+        assert_eq!(segment_file_name(12, &with_summary), "00012_20220102T030405Z-20220102T040607Z_b.json");
+
+        let mut no_summary = SummarisedContent::empty();
+        no_summary.push_event(&blocks[3]);
+        assert_eq!(segment_file_name(0, &no_summary), "00000_undated_HEAD.json");
+        assert_eq!(segment_file_name(3, &no_summary), "00003_undated_d.json");
+    }
+
+    /// A sink that fails to write a particular segment
+    struct FailingSink {
+        inner: MemorySink,
+        fail_at: usize,
+    }
+
+    impl OutputSink for FailingSink {
+        fn begin_liveblog(&mut self, main:&MainContent, stats:&Stats) -> Result<(), Box<dyn Error>> {
+            self.inner.begin_liveblog(main, stats)
+        }
+
+        fn write_segment(&mut self, index:usize, segment:&SummarisedContent) -> Result<(), Box<dyn Error>> {
+            if index==self.fail_at {
+                Err("disk full".into())
+            } else {
+                self.inner.write_segment(index, segment)
+            }
+        }
+
+        fn write_stats(&mut self, stats:&Stats) -> Result<(), Box<dyn Error>> {
+            self.inner.write_stats(stats)
+        }
+
+        fn finish_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+            self.inner.finish_liveblog()
+        }
+
+        fn abort_liveblog(&mut self) -> Result<(), Box<dyn Error>> {
+            self.inner.abort_liveblog()
+        }
+
+        fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+            self.inner.flush()
+        }
+
+        fn output_files(&self) -> Vec<PathBuf> {
+            self.inner.output_files()
+        }
+    }
+
+    #[test]
+    pub fn test_write_liveblog_propagates_errors() {
+        let blocks = [block("a").summary(true).build(), block("b").summary(true).build(), block("c").summary(true).build()];
+        let main = block("main").main();
+        let segments:Vec<SummarisedContent> = blocks.iter().map(|b| SummarisedContent::new(b, vec!())).collect();
+        let mut sink = FailingSink { inner: MemorySink::default(), fail_at: 1 };
+
+        let result = write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build());
+
+        assert!(result.unwrap_err().to_string().contains("disk full"));
+        let written = &sink.inner.liveblogs[0];
+        assert_eq!(written.segments.len(), 1);
+        assert!(written.stats.is_none());
+        assert!(!written.finished);
+        assert!(written.aborted);
+    }
+
+    #[test]
+    pub fn test_directory_sink_reharvest_clears_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").summary(true).build(), block("b").summary(true).build()];
+        let main = block("main").main();
+        let segments:Vec<SummarisedContent> = blocks.iter().map(|b| SummarisedContent::new(b, vec!())).collect();
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false)));
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+        assert!(dir.path().join("news/live/blog").join(COMPLETE_MARKER).exists());
+
+        //starting to write it again removes the marker until it is finished
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false)));
+        sink.begin_liveblog(&MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+        assert!(!dir.path().join("news/live/blog").join(COMPLETE_MARKER).exists());
+    }
+
+    #[test]
+    pub fn test_directory_sink_reharvest_removes_old_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = [block("a").summary(true).build(), block("b").summary(true).build()];
+        let main = block("main").main();
+        let segments:Vec<SummarisedContent> = blocks.iter().map(|b| SummarisedContent::new(b, vec!())).collect();
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false))).with_markdown(true);
+        write_liveblog(&mut sink, &segments, &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+        let liveblog_dir = dir.path().join("news/live/blog");
+        assert!(read_to_string(liveblog_dir.join("00000_20220102T030405Z-20220102T030405Z_a.md")).unwrap().starts_with("# "));
+        let meta:serde_json::Value = serde_json::from_str(&read_to_string(liveblog_dir.join("META.json")).unwrap()).unwrap();
+        assert_eq!(meta["segments"][1]["markdown_file"], "00001_20220102T030405Z-20220102T030405Z_b.md");
+
+        //the second time round it is only one segment, so the file for the other should go, along with the Markdown
+        let mut sink = DirectorySink::new(Box::new(LooseFiles::new(dir.path().to_str().unwrap(), false)));
+        write_liveblog(&mut sink, &segments[1..], &MainContent { main: &main, standfirst: None }, &stats("news/live/blog").build()).unwrap();
+        assert!(!liveblog_dir.join("00000_20220102T030405Z-20220102T030405Z_a.json").exists());
+        assert!(!liveblog_dir.join("00000_20220102T030405Z-20220102T030405Z_a.md").exists());
+        assert!(!liveblog_dir.join("00001_20220102T030405Z-20220102T030405Z_b.md").exists());
+        assert!(!liveblog_dir.join("00001_20220102T030405Z-20220102T030405Z_b.json").exists());
+        assert!(liveblog_dir.join("00000_20220102T030405Z-20220102T030405Z_b.json").exists());
+        assert!(liveblog_dir.join(COMPLETE_MARKER).exists());
+    }
+}